@@ -0,0 +1,406 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{anyhow, Context};
+
+use crate::config::config::StorageConfig;
+use crate::instance::instance::{DiskConfig, InstanceRuntime};
+use crate::network::network::NetworkTypeRuntime;
+use crate::runtime::runtime::Runtime;
+use crate::state::state::Resources;
+use crate::virt_manager::virt_manager::{self, VirtManager, DOMAIN_TYPE};
+
+// side effects on the machine virt-rs runs on: bridges, taps and the files seed isos are built from
+pub trait Host{
+    // returns stdout
+    fn run_command(&self, program: &str, args: &[String]) -> anyhow::Result<String>;
+    // creates missing parent directories
+    fn write_file(&self, path: &str, content: &str) -> anyhow::Result<()>;
+    fn read_file(&self, path: &str) -> anyhow::Result<Vec<u8>>;
+    // files and directories, nothing happens if the path doesn't exist
+    fn remove_path(&self, path: &str) -> anyhow::Result<()>;
+    fn path_exists(&self, path: &str) -> bool;
+}
+
+// what deploying a topology needs from a hypervisor. the provided methods are the code path of
+// up, implementations only supply the single steps, so a dry run takes the same path as a deployment
+pub trait Backend{
+    fn host(&self) -> &dyn Host;
+    fn domain_type(&self) -> &str;
+    // progress of the deployment for the user
+    fn report(&self, message: &str);
+    // None if the libvirt network is not defined, otherwise whether it is active
+    fn network_active(&self, name: &str) -> anyhow::Result<Option<bool>>;
+    fn define_network(&self, name: &str, xml: &str) -> anyhow::Result<()>;
+    fn start_network(&self, name: &str) -> anyhow::Result<()>;
+    // None if the domain is not defined, otherwise whether it is active
+    fn domain_active(&self, name: &str) -> anyhow::Result<Option<bool>>;
+    // None if the domain is not defined
+    fn domain_uuid(&self, name: &str) -> anyhow::Result<Option<String>>;
+    // a leftover volume of the same name is replaced
+    fn create_disk(&self, storage: &StorageConfig, volume: &str, image: &str, disk: DiskConfig) -> anyhow::Result<()>;
+    // a volume with the content of a file on the host, a leftover volume of the same name is replaced
+    fn upload_volume(&self, storage: &StorageConfig, volume: &str, source: &str) -> anyhow::Result<()>;
+    fn define_domain(&self, name: &str, xml: &str) -> anyhow::Result<()>;
+    fn create_domain(&self, name: &str, xml: &str) -> anyhow::Result<()>;
+    fn start_domain(&self, name: &str) -> anyhow::Result<()>;
+    fn set_autostart(&self, kind: &'static str, name: &str, autostart: bool) -> anyhow::Result<()>;
+
+    // every instance gets its own result, everything created is recorded in resources, also
+    // when creating the topology fails halfway
+    fn create_topology(&self, runtime: &Runtime, resources: &mut Resources) -> anyhow::Result<BTreeMap<String, anyhow::Result<()>>> {
+        for name in runtime.networks.keys(){
+            self.create_network(name, runtime, resources)?;
+        }
+        let mut results = BTreeMap::new();
+        for (name, instance) in &runtime.instances{
+            // a domain of the same name that isn't this instance is neither touched nor recorded
+            let result = self.check_owner(name, instance).and_then(|_| {
+                resources.instances.insert(name.clone(), VirtManager::instance_resources(name, instance, &runtime.storage));
+                self.create_instance(name, instance, runtime)
+            });
+            match &result{
+                Ok(_) => self.report(&format!("instance {} created", name)),
+                Err(e) => self.report(&format!("instance {} failed: {:#}", name, e)),
+            }
+            results.insert(name.clone(), result);
+        }
+        Ok(results)
+    }
+
+    fn create_network(&self, name: &str, runtime: &Runtime, resources: &mut Resources) -> anyhow::Result<()> {
+        let network = runtime.networks.get(name).ok_or(anyhow!("network {} not found", name))?;
+        match &network.network_type{
+            NetworkTypeRuntime::Unmanaged{bridge, ..} => {
+                let created = virt_manager::create_bridge(self.host(), bridge, &runtime.name)?;
+                // also recorded when an earlier run created it, teardown checks the tag anyway
+                resources.bridges.insert(name.to_string(), bridge.clone());
+                if created{
+                    self.report(&format!("network {} created bridge {}", name, bridge.name));
+                }
+            },
+            NetworkTypeRuntime::Libvirt{name: libvirt_name, ..} => {
+                match self.network_active(libvirt_name)?{
+                    Some(true) => {},
+                    Some(false) => self.start_network(libvirt_name)?,
+                    None => {
+                        let xml = VirtManager::network_xml(name, runtime)?;
+                        self.define_network(libvirt_name, &xml)
+                            .with_context(|| format!("failed to define libvirt network {}", libvirt_name))?;
+                        resources.libvirt_networks.insert(name.to_string(), libvirt_name.clone());
+                        self.set_autostart("network", libvirt_name, true)?;
+                        self.start_network(libvirt_name)?;
+                        self.report(&format!("network {} created libvirt network {}", name, libvirt_name));
+                    },
+                }
+            },
+            NetworkTypeRuntime::Managed{..} => {},
+        }
+        Ok(())
+    }
+
+    // domains are found by name, the uuid tells whether it is the one of the instance
+    fn check_owner(&self, name: &str, instance: &InstanceRuntime) -> anyhow::Result<()> {
+        match self.domain_uuid(name)?{
+            Some(uuid) if uuid != instance.uuid => Err(anyhow!("domain {} already exists with uuid {}, it is not instance {} of this topology", name, uuid, name)),
+            _ => Ok(()),
+        }
+    }
+
+    fn create_instance(&self, name: &str, instance: &InstanceRuntime, runtime: &Runtime) -> anyhow::Result<()> {
+        if let Some(active) = self.domain_active(name)?{
+            // the domain is already there, make sure it runs instead of recreating its disk
+            if instance.persistent{
+                self.set_autostart("domain", name, instance.autostart)?;
+            }
+            if !active{
+                self.start_domain(name)?;
+                self.report(&format!("instance {} already defined, started it", name));
+            } else {
+                self.report(&format!("instance {} already running", name));
+            }
+            return Ok(());
+        }
+        self.create_disk(&runtime.storage, &virt_manager::disk_volume(name), &instance.image, instance.disk)?;
+        self.create_seed_iso(name, instance, runtime)?;
+        let xml = VirtManager::domain_xml(name, instance, &runtime.storage, self.domain_type())?;
+        if instance.persistent{
+            self.define_domain(name, &xml)?;
+            self.set_autostart("domain", name, instance.autostart)?;
+            self.start_domain(name)?;
+        } else {
+            self.create_domain(name, &xml)?;
+        }
+        Ok(())
+    }
+
+    // the iso is built on the host and uploaded, which works for remote pools as well
+    fn create_seed_iso(&self, name: &str, instance: &InstanceRuntime, runtime: &Runtime) -> anyhow::Result<()> {
+        let files = virt_manager::seed_iso_files(name, instance, runtime)?;
+        for (path, content) in &files{
+            self.host().write_file(path, content)?;
+        }
+        let (program, args) = virt_manager::seed_iso_command(name, &files);
+        let result = self.host().run_command(program, &args)
+            .and_then(|_| self.upload_volume(&runtime.storage, &virt_manager::seed_iso_volume(name), &virt_manager::seed_iso_file(name)));
+        self.host().remove_path(&virt_manager::cloud_init_dir(name))?;
+        result.with_context(|| format!("failed to create seed iso for instance {}", name))
+    }
+}
+
+// the machine virt-rs runs on
+#[derive(Debug, Default)]
+pub struct LocalHost;
+
+impl Host for LocalHost{
+    fn run_command(&self, program: &str, args: &[String]) -> anyhow::Result<String>{
+        let output = Command::new(program).args(args).output()
+            .with_context(|| format!("failed to run {}", program))?;
+        if !output.status.success(){
+            return Err(anyhow!("{} {} failed: {}", program, args.join(" "), String::from_utf8_lossy(&output.stderr).trim()));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    fn write_file(&self, path: &str, content: &str) -> anyhow::Result<()>{
+        if let Some(parent) = Path::new(path).parent(){
+            std::fs::create_dir_all(parent).with_context(|| format!("failed to create {}", parent.display()))?;
+        }
+        std::fs::write(path, content).with_context(|| format!("failed to write {}", path))
+    }
+
+    fn read_file(&self, path: &str) -> anyhow::Result<Vec<u8>>{
+        std::fs::read(path).with_context(|| format!("failed to read {}", path))
+    }
+
+    fn remove_path(&self, path: &str) -> anyhow::Result<()>{
+        let path = Path::new(path);
+        if path.is_dir(){
+            std::fs::remove_dir_all(path).with_context(|| format!("failed to remove {}", path.display()))?;
+        } else if path.exists(){
+            std::fs::remove_file(path).with_context(|| format!("failed to remove {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    fn path_exists(&self, path: &str) -> bool{
+        Path::new(path).exists()
+    }
+}
+
+// a side effect on the host, in the order the backend performs them
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operation{
+    Command{
+        program: String,
+        args: Vec<String>,
+    },
+    WriteFile{
+        path: String,
+        content: String,
+    },
+    RemovePath{
+        path: String,
+    },
+    DefineNetwork{
+        name: String,
+        xml: String,
+    },
+    StartNetwork{
+        name: String,
+    },
+    CreateDisk{
+        pool: String,
+        volume: String,
+        image: String,
+        disk: DiskConfig,
+    },
+    // a volume uploaded from a local file
+    UploadVolume{
+        pool: String,
+        volume: String,
+        source: String,
+    },
+    DefineDomain{
+        name: String,
+        xml: String,
+    },
+    // transient, gone once it stops
+    CreateDomain{
+        name: String,
+        xml: String,
+    },
+    StartDomain{
+        name: String,
+    },
+    Autostart{
+        kind: &'static str,
+        name: String,
+        autostart: bool,
+    },
+}
+
+impl fmt::Display for Operation{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            Operation::Command{program, args} => write!(f, "run {} {}", program, args.join(" ")),
+            Operation::WriteFile{path, content} => write!(f, "write {}\n{}", path, indent(content)),
+            Operation::RemovePath{path} => write!(f, "remove {}", path),
+            Operation::DefineNetwork{name, xml} => write!(f, "define network {}\n{}", name, indent(xml)),
+            Operation::StartNetwork{name} => write!(f, "start network {}", name),
+            Operation::CreateDisk{pool, volume, image, disk} => write!(f, "create volume {} in pool {} from image {} as {}", volume, pool, image, disk),
+            Operation::UploadVolume{pool, volume, source} => write!(f, "upload {} to volume {} in pool {}", source, volume, pool),
+            Operation::DefineDomain{name, xml} => write!(f, "define domain {}\n{}", name, indent(xml)),
+            Operation::CreateDomain{name, xml} => write!(f, "create transient domain {}\n{}", name, indent(xml)),
+            Operation::StartDomain{name} => write!(f, "start domain {}", name),
+            Operation::Autostart{kind, name, autostart} => write!(f, "set autostart of {} {} to {}", kind, name, autostart),
+        }
+    }
+}
+
+fn indent(text: &str) -> String{
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| format!("    {}", line))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// records what a deployment would do. what already exists is looked up read-only on the
+// hypervisor and this machine when inspecting, otherwise the host has the storage pool and
+// images but nothing else of the topology yet. files it would read back are empty unless given
+#[derive(Default)]
+pub struct DryRun{
+    operations: RefCell<Vec<Operation>>,
+    files: BTreeMap<String, Vec<u8>>,
+    hypervisor: Option<VirtManager>,
+}
+
+impl DryRun{
+    pub fn new() -> DryRun{
+        DryRun::default()
+    }
+
+    pub fn inspecting(hypervisor: VirtManager) -> DryRun{
+        DryRun{
+            hypervisor: Some(hypervisor),
+            ..Default::default()
+        }
+    }
+
+    // content of files the commands would have made, like the seed isos
+    pub fn with_files(files: BTreeMap<String, Vec<u8>>) -> DryRun{
+        DryRun{
+            files,
+            ..Default::default()
+        }
+    }
+
+    pub fn operations(&self) -> Vec<Operation>{
+        self.operations.borrow().clone()
+    }
+
+    fn record(&self, operation: Operation) -> anyhow::Result<()>{
+        self.operations.borrow_mut().push(operation);
+        Ok(())
+    }
+}
+
+impl Host for DryRun{
+    fn run_command(&self, program: &str, args: &[String]) -> anyhow::Result<String>{
+        self.record(Operation::Command{program: program.to_string(), args: args.to_vec()})?;
+        Ok(String::new())
+    }
+
+    fn write_file(&self, path: &str, content: &str) -> anyhow::Result<()>{
+        self.record(Operation::WriteFile{path: path.to_string(), content: content.to_string()})
+    }
+
+    fn read_file(&self, path: &str) -> anyhow::Result<Vec<u8>>{
+        match self.files.get(path){
+            Some(content) => Ok(content.clone()),
+            None if self.hypervisor.is_some() && LocalHost.path_exists(path) => LocalHost.read_file(path),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn remove_path(&self, path: &str) -> anyhow::Result<()>{
+        self.record(Operation::RemovePath{path: path.to_string()})
+    }
+
+    fn path_exists(&self, path: &str) -> bool{
+        self.files.contains_key(path) || (self.hypervisor.is_some() && LocalHost.path_exists(path))
+    }
+}
+
+impl Backend for DryRun{
+    fn host(&self) -> &dyn Host{
+        self
+    }
+
+    fn domain_type(&self) -> &str{
+        match &self.hypervisor{
+            Some(hypervisor) => hypervisor.domain_type(),
+            None => DOMAIN_TYPE,
+        }
+    }
+
+    // the operations are the report
+    fn report(&self, _message: &str){}
+
+    fn network_active(&self, name: &str) -> anyhow::Result<Option<bool>>{
+        match &self.hypervisor{
+            Some(hypervisor) => hypervisor.network_active(name),
+            None => Ok(None),
+        }
+    }
+
+    fn define_network(&self, name: &str, xml: &str) -> anyhow::Result<()>{
+        self.record(Operation::DefineNetwork{name: name.to_string(), xml: xml.to_string()})
+    }
+
+    fn start_network(&self, name: &str) -> anyhow::Result<()>{
+        self.record(Operation::StartNetwork{name: name.to_string()})
+    }
+
+    fn domain_active(&self, name: &str) -> anyhow::Result<Option<bool>>{
+        match &self.hypervisor{
+            Some(hypervisor) => hypervisor.domain_active(name),
+            None => Ok(None),
+        }
+    }
+
+    fn domain_uuid(&self, name: &str) -> anyhow::Result<Option<String>>{
+        match &self.hypervisor{
+            Some(hypervisor) => hypervisor.domain_uuid(name),
+            None => Ok(None),
+        }
+    }
+
+    fn create_disk(&self, storage: &StorageConfig, volume: &str, image: &str, disk: DiskConfig) -> anyhow::Result<()>{
+        self.record(Operation::CreateDisk{pool: storage.pool.clone(), volume: volume.to_string(), image: image.to_string(), disk})
+    }
+
+    fn upload_volume(&self, storage: &StorageConfig, volume: &str, source: &str) -> anyhow::Result<()>{
+        self.record(Operation::UploadVolume{pool: storage.pool.clone(), volume: volume.to_string(), source: source.to_string()})
+    }
+
+    fn define_domain(&self, name: &str, xml: &str) -> anyhow::Result<()>{
+        self.record(Operation::DefineDomain{name: name.to_string(), xml: xml.to_string()})
+    }
+
+    fn create_domain(&self, name: &str, xml: &str) -> anyhow::Result<()>{
+        self.record(Operation::CreateDomain{name: name.to_string(), xml: xml.to_string()})
+    }
+
+    fn start_domain(&self, name: &str) -> anyhow::Result<()>{
+        self.record(Operation::StartDomain{name: name.to_string()})
+    }
+
+    fn set_autostart(&self, kind: &'static str, name: &str, autostart: bool) -> anyhow::Result<()>{
+        self.record(Operation::Autostart{kind, name: name.to_string(), autostart})
+    }
+}
@@ -0,0 +1 @@
+pub mod backend;
\ No newline at end of file
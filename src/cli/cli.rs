@@ -0,0 +1,438 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use clap::{Parser, Subcommand, ValueEnum};
+use crate::backend::backend::{Backend, DryRun};
+use crate::cloud_init::cloud_init::CloudInit;
+use crate::config::config::Config;
+use crate::plan::plan::Plan;
+use crate::runtime::runtime::Runtime;
+use crate::state::state::{Resources, State, DEFAULT_STATE_DIR};
+use crate::virt_manager::virt_manager::{InstanceStatus, Lifecycle, VirtManager, DEFAULT_URI, DOMAIN_TYPE};
+
+#[derive(Parser)]
+#[clap(version = "0.1.0")]
+pub struct Opts {
+    #[clap(long, short, global = true, default_value = "config.yaml")]
+    pub config: String,
+    /// Directory holding the state of deployed topologies, relative to the directory of the config
+    #[clap(long, global = true, default_value = DEFAULT_STATE_DIR)]
+    pub state_dir: String,
+    /// Libvirt connection uri, overrides the topology and LIBVIRT_DEFAULT_URI. Remote hypervisors only support libvirt and managed networks
+    #[clap(long, global = true)]
+    pub connect: Option<String>,
+    #[clap(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Yaml,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Write an example topology to the config file
+    Init {
+        #[clap(long, short)]
+        force: bool,
+    },
+    /// Show what apply would change in the deployed topology
+    Plan,
+    /// Create, update and destroy only what changed since the topology was deployed
+    Apply {
+        /// Seconds to wait for a graceful shutdown before replaced instances are destroyed
+        #[clap(long, short, default_value = "60")]
+        timeout: u64,
+    },
+    /// Create all instances of a topology that is not deployed yet, apply changes a deployed one
+    Up {
+        /// Print what would be done instead of doing it, what already exists is looked up read-only
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Destroy all instances of the topology and remove their disks and seed isos
+    Down {
+        /// Seconds to wait for a graceful shutdown before the instances are destroyed
+        #[clap(long, short, default_value = "60")]
+        timeout: u64,
+    },
+    /// Show the live state, resource usage and guest addresses of all instances of the topology
+    Status {
+        #[clap(long, short, value_enum, default_value = "table")]
+        output: OutputFormat,
+    },
+    /// Start instances
+    Start {
+        /// Instance names or glob patterns like router*, all instances if none are given
+        instances: Vec<String>,
+    },
+    /// Shut instances down via ACPI, instances with persistent: false are undefined by libvirt once they stop
+    Shutdown {
+        /// Instance names or glob patterns like router*, all instances if none are given
+        instances: Vec<String>,
+        /// Seconds to wait for the guests to power off
+        #[clap(long, short, default_value = "60")]
+        timeout: u64,
+        /// Destroy instances that did not power off within the timeout
+        #[clap(long, short)]
+        force: bool,
+    },
+    /// Power instances off immediately, disks are kept and so is the definition unless the instance has persistent: false
+    Destroy {
+        /// Instance names or glob patterns like router*, all instances if none are given
+        instances: Vec<String>,
+    },
+    /// Reboot running instances
+    Reboot {
+        /// Instance names or glob patterns like router*, all instances if none are given
+        instances: Vec<String>,
+    },
+    /// Pause running instances
+    Suspend {
+        /// Instance names or glob patterns like router*, all instances if none are given
+        instances: Vec<String>,
+    },
+    /// Continue suspended instances
+    Resume {
+        /// Instance names or glob patterns like router*, all instances if none are given
+        instances: Vec<String>,
+    },
+    /// Print the config, the computed runtime or the deployed state as yaml
+    Show {
+        #[clap(subcommand)]
+        object: ShowObject,
+    },
+    /// Render generated artifacts without touching libvirt
+    Render {
+        #[clap(subcommand)]
+        object: RenderObject,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ShowObject {
+    Config,
+    Runtime,
+    State,
+}
+
+#[derive(Subcommand)]
+pub enum RenderObject {
+    /// Render the domain xml of an instance
+    Xml {
+        instance: String,
+    },
+    /// Render the xml of a network created by virt-rs
+    NetworkXml {
+        network: String,
+    },
+    /// Render the cloud-init user-data, meta-data and network-config of an instance
+    CloudInit {
+        instance: String,
+    },
+}
+
+pub fn run(opts: Opts) -> anyhow::Result<()> {
+    match opts.command{
+        Command::Init{force} => init(&opts.config, force),
+        Command::Plan => {
+            let (config, _, state) = load(&opts.config, &opts.state_dir)?;
+            let runtime = build_runtime(&config, state.as_ref())?;
+            println!("{}", Plan::new(&runtime, state.as_ref().map(|state| &state.runtime)));
+            Ok(())
+        },
+        Command::Apply{timeout} => {
+            let (config, state_path, state) = load(&opts.config, &opts.state_dir)?;
+            let runtime = build_runtime(&config, state.as_ref())?;
+            let (deployed, mut resources) = match state{
+                Some(state) => (Some(state.runtime), state.resources),
+                None => (None, Resources::default()),
+            };
+            let plan = Plan::new(&runtime, deployed.as_ref());
+            println!("{}", plan);
+            if plan.is_empty(){
+                return Ok(());
+            }
+            let virt_manager = connect(opts.connect.as_deref(), &config)?;
+            virt_manager.check_local(&runtime, &resources)?;
+            let results = virt_manager.apply(&plan, &runtime, &mut resources, Duration::from_secs(timeout));
+            let failed: Vec<&String> = match &results{
+                Ok(results) => results.iter().filter(|(_, result)| result.is_err()).map(|(name, _)| name).collect(),
+                // apply stopped early, none of the planned instances is known to be applied
+                Err(_) => plan.instances.keys().collect(),
+            };
+            State::new(runtime.applied(deployed.as_ref(), &failed), resources).save(&state_path)?;
+            check_results(&results?)
+        },
+        Command::Up{dry_run} => {
+            let (config, state_path, state) = load(&opts.config, &opts.state_dir)?;
+            // up only creates, changes to a deployed topology would go unnoticed by the next plan
+            if state.is_some(){
+                return Err(anyhow!("topology {} is already deployed according to {}, use apply to change it or down to remove it",
+                    config.topology_name(), state_path.display()));
+            }
+            let runtime = Runtime::build(&config)?;
+            if dry_run{
+                // what already exists is skipped by up, the dry run has to know about it as well
+                let dry_run = match VirtManager::read_only(&connect_uri(opts.connect.as_deref(), &config)){
+                    Ok(hypervisor) => {
+                        hypervisor.check_local(&runtime, &Resources::default())?;
+                        DryRun::inspecting(hypervisor)
+                    },
+                    Err(e) => {
+                        eprintln!("warning: {:#}, assuming nothing of the topology exists yet", e);
+                        DryRun::new()
+                    },
+                };
+                let results = dry_run.create_topology(&runtime, &mut Resources::default())?;
+                for operation in dry_run.operations(){
+                    println!("{}", operation);
+                }
+                for (name, result) in &results{
+                    if let Err(e) = result{
+                        println!("instance {} failed: {:#}", name, e);
+                    }
+                }
+                return check_results(&results);
+            }
+            let mut resources = Resources::default();
+            let virt_manager = connect(opts.connect.as_deref(), &config)?;
+            virt_manager.check_local(&runtime, &resources)?;
+            let results = virt_manager.create_topology(&runtime, &mut resources);
+            let failed: Vec<String> = match &results{
+                Ok(results) => results.iter().filter(|(_, result)| result.is_err()).map(|(name, _)| name.clone()).collect(),
+                Err(_) => runtime.instances.keys().cloned().collect(),
+            };
+            // saved before checking the results, a partially created topology still has to be torn down.
+            // failed instances are left out of the runtime, so the next apply creates them again
+            let failed: Vec<&String> = failed.iter().collect();
+            State::new(runtime.applied(None, &failed), resources).save(&state_path)?;
+            check_results(&results?)
+        },
+        Command::Down{timeout} => {
+            let (config, state_path, state) = load(&opts.config, &opts.state_dir)?;
+            let mut state = match state{
+                Some(state) => state,
+                None => {
+                    println!("no state found at {}, tearing down what the config describes", state_path.display());
+                    let runtime = Runtime::build(&config)?;
+                    let resources = Resources::from(&runtime);
+                    State::new(runtime, resources)
+                },
+            };
+            let virt_manager = connect(opts.connect.as_deref(), &config)?;
+            virt_manager.check_local(&state.runtime, &state.resources)?;
+            let results = virt_manager.destroy_topology(&state.runtime.name, &mut state.resources, Duration::from_secs(timeout));
+            if state.resources.is_empty(){
+                State::remove(&state_path)?;
+            } else {
+                state.save(&state_path)?;
+            }
+            check_results(&results?)
+        },
+        Command::Status{output} => {
+            let (config, _, state) = load(&opts.config, &opts.state_dir)?;
+            // without state the instances may still have been created by up, under their own names
+            let (runtime, resources) = match state{
+                Some(state) => (state.runtime, state.resources),
+                None => {
+                    let runtime = Runtime::build(&config)?;
+                    let resources = Resources::from(&runtime);
+                    (runtime, resources)
+                },
+            };
+            let virt_manager = connect(opts.connect.as_deref(), &config)?;
+            let mut statuses = Vec::new();
+            for (name, instance) in &runtime.instances{
+                let domain = resources.instances.get(name).map(|resources| resources.domain.as_str()).unwrap_or(name);
+                statuses.push(virt_manager.instance_status(name, domain, instance, &runtime)?);
+            }
+            match output{
+                OutputFormat::Table => print_status(&statuses),
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&statuses)?),
+                OutputFormat::Yaml => print!("{}", serde_yaml::to_string(&statuses)?),
+            }
+            Ok(())
+        },
+        Command::Start{instances} => lifecycle(&opts.config, &opts.state_dir, opts.connect.as_deref(), &instances, Lifecycle::Start),
+        Command::Shutdown{instances, timeout, force} => {
+            lifecycle(&opts.config, &opts.state_dir, opts.connect.as_deref(), &instances, Lifecycle::Shutdown{timeout: Duration::from_secs(timeout), force})
+        },
+        Command::Destroy{instances} => lifecycle(&opts.config, &opts.state_dir, opts.connect.as_deref(), &instances, Lifecycle::Destroy),
+        Command::Reboot{instances} => lifecycle(&opts.config, &opts.state_dir, opts.connect.as_deref(), &instances, Lifecycle::Reboot),
+        Command::Suspend{instances} => lifecycle(&opts.config, &opts.state_dir, opts.connect.as_deref(), &instances, Lifecycle::Suspend),
+        Command::Resume{instances} => lifecycle(&opts.config, &opts.state_dir, opts.connect.as_deref(), &instances, Lifecycle::Resume),
+        Command::Show{object} => {
+            let (config, state_path, state) = load(&opts.config, &opts.state_dir)?;
+            let serialized = match object{
+                ShowObject::Config => serde_yaml::to_string(&config)?,
+                ShowObject::Runtime => serde_yaml::to_string(&build_runtime(&config, state.as_ref())?)?,
+                ShowObject::State => {
+                    let state = state.ok_or_else(|| anyhow!("no state found at {}", state_path.display()))?;
+                    serde_yaml::to_string(&state)?
+                },
+            };
+            println!("{}", serialized);
+            Ok(())
+        },
+        Command::Render{object} => {
+            let (config, _, state) = load(&opts.config, &opts.state_dir)?;
+            let runtime = build_runtime(&config, state.as_ref())?;
+            match object{
+                RenderObject::Xml{instance: name} => {
+                    let instance = runtime.instances.get(&name).ok_or(anyhow!("instance {} not found", name))?;
+                    println!("{}", VirtManager::domain_xml(&name, instance, &runtime.storage, DOMAIN_TYPE)?);
+                },
+                RenderObject::NetworkXml{network: name} => {
+                    println!("{}", VirtManager::network_xml(&name, &runtime)?);
+                },
+                RenderObject::CloudInit{instance: name} => {
+                    let instance = runtime.instances.get(&name).ok_or(anyhow!("instance {} not found", name))?;
+                    let cloud_init = CloudInit::build(&name, instance, &runtime)?;
+                    println!("# user-data\n{}", cloud_init.user_data);
+                    println!("# meta-data\n{}", cloud_init.meta_data);
+                    println!("# network-config\n{}", cloud_init.network_config);
+                },
+            }
+            Ok(())
+        },
+    }
+}
+
+// the config, where the state of its topology lives and the state itself if it was deployed
+fn load(path: &str, state_dir: &str) -> anyhow::Result<(Config, PathBuf, Option<State>)> {
+    let config = load_config(path)?;
+    let state_path = State::path(path, state_dir, config.topology_name());
+    let state = State::load(&state_path)?;
+    Ok((config, state_path, state))
+}
+
+// addresses handed out by a previous deployment are kept stable
+fn build_runtime(config: &Config, state: Option<&State>) -> anyhow::Result<Runtime> {
+    let runtime = match state{
+        Some(state) => Runtime::rebuild(config, &state.runtime)?,
+        None => Runtime::build(config)?,
+    };
+    Ok(runtime)
+}
+
+pub fn load_config(path: &str) -> anyhow::Result<Config> {
+    let config = std::fs::read_to_string(path).with_context(|| format!("failed to read config {}", path))?;
+    // a plain yaml value rejects duplicate keys, which the maps in Config silently overwrite
+    serde_yaml::from_str::<serde_yaml::Value>(&config).with_context(|| format!("failed to parse config {}", path))?;
+    let mut config: Config = serde_yaml::from_str(&config).with_context(|| format!("failed to parse config {}", path))?;
+    config.resolve_templates(path);
+    for warning in config.validate().with_context(|| format!("invalid config {}", path))?{
+        eprintln!("warning: {}", warning);
+    }
+    Ok(config)
+}
+
+fn init(path: &str, force: bool) -> anyhow::Result<()> {
+    if Path::new(path).exists() && !force{
+        return Err(anyhow!("{} already exists, use --force to overwrite it", path));
+    }
+    let serialized = serde_yaml::to_string(&Config::example())?;
+    std::fs::write(path, serialized).with_context(|| format!("failed to write config {}", path))?;
+    println!("example topology written to {}", path);
+    Ok(())
+}
+
+// --connect wins over the topology, LIBVIRT_DEFAULT_URI only applies if neither sets one
+fn connect(uri: Option<&str>, config: &Config) -> anyhow::Result<VirtManager> {
+    VirtManager::new(&connect_uri(uri, config))
+}
+
+fn connect_uri(uri: Option<&str>, config: &Config) -> String {
+    uri.map(str::to_string)
+        .or_else(|| config.connect.clone())
+        .or_else(|| std::env::var("LIBVIRT_DEFAULT_URI").ok())
+        .unwrap_or(DEFAULT_URI.to_string())
+}
+
+fn lifecycle(config: &str, state_dir: &str, uri: Option<&str>, patterns: &[String], operation: Lifecycle) -> anyhow::Result<()> {
+    let (config, _, state) = load(config, state_dir)?;
+    // instances of a deployed topology are taken from its state, their domains may have other names
+    let domains: BTreeMap<String, (String, bool)> = match &state{
+        Some(state) => state.resources.instances.iter().map(|(name, instance)| {
+            let persistent = state.runtime.instances.get(name).is_none_or(|instance| instance.persistent);
+            (name.clone(), (instance.domain.clone(), persistent))
+        }).collect(),
+        None => config.instances.iter().map(|(name, instance)| (name.clone(), (name.clone(), instance.persistent))).collect(),
+    };
+    let selected = select(domains.keys(), patterns)?;
+    let virt_manager = connect(uri, &config)?;
+    let mut results = BTreeMap::new();
+    for name in selected{
+        let (domain, persistent) = &domains[name];
+        let result = virt_manager.lifecycle(name, domain, *persistent, operation);
+        if let Err(e) = &result{
+            println!("instance {} failed: {:#}", name, e);
+        }
+        results.insert(name.clone(), result);
+    }
+    check_results(&results)
+}
+
+// names matching any of the glob patterns, all names without patterns
+fn select<'a>(names: impl Iterator<Item = &'a String> + Clone, patterns: &[String]) -> anyhow::Result<Vec<&'a String>> {
+    if patterns.is_empty(){
+        return Ok(names.collect());
+    }
+    let mut matchers = Vec::new();
+    for pattern in patterns{
+        let matcher = glob::Pattern::new(pattern).with_context(|| format!("invalid instance pattern {}", pattern))?;
+        if !names.clone().any(|name| matcher.matches(name)){
+            return Err(anyhow!("no instance matches {}", pattern));
+        }
+        matchers.push(matcher);
+    }
+    Ok(names.filter(|name| matchers.iter().any(|matcher| matcher.matches(name))).collect())
+}
+
+fn print_status(statuses: &[InstanceStatus]){
+    println!("{:<20} {:<12} {:>10} {:>5} {:>10} {:>16}", "INSTANCE", "STATE", "UPTIME", "VCPU", "CPU TIME", "MEMORY");
+    for status in statuses{
+        let uptime = status.uptime_secs.map(format_duration).unwrap_or_else(|| "-".to_string());
+        let memory = match status.memory_used_kib{
+            Some(used) => format!("{}/{} MiB", used / 1024, status.memory_kib / 1024),
+            None => format!("{} MiB", status.memory_kib / 1024),
+        };
+        println!("{:<20} {:<12} {:>10} {:>5} {:>10} {:>16}",
+            status.name, status.state, uptime, status.vcpu, format_duration(status.cpu_time_secs as u64), memory);
+        for interface in &status.interfaces{
+            // configured addresses are shown until the guest reports its own
+            let addresses = match interface.source{
+                Some(source) => format!("{} ({})", interface.addresses.join(", "), source),
+                None => interface.configured.join(", "),
+            };
+            println!("  {:<18} {:<17} {:<15} {}", interface.name, interface.mac, interface.tap.as_deref().unwrap_or("-"), addresses);
+        }
+    }
+}
+
+fn format_duration(secs: u64) -> String{
+    let (days, hours, minutes, secs) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    if days > 0{
+        format!("{}d{}h", days, hours)
+    } else if hours > 0{
+        format!("{}h{}m", hours, minutes)
+    } else if minutes > 0{
+        format!("{}m{}s", minutes, secs)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+fn check_results(results: &BTreeMap<String, anyhow::Result<()>>) -> anyhow::Result<()> {
+    let failed: Vec<&String> = results.iter().filter(|(_, result)| result.is_err()).map(|(name, _)| name).collect();
+    if !failed.is_empty(){
+        return Err(anyhow!("{} of {} instances failed: {:?}", failed.len(), results.len(), failed));
+    }
+    Ok(())
+}
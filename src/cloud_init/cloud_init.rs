@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use handlebars::Handlebars;
+use serde::Serialize;
+use serde_json::json;
+use crate::instance::instance::InstanceRuntime;
+use crate::network::network::NetworkTypeRuntime;
+use crate::runtime::runtime::Runtime;
+
+pub struct CloudInit{
+    pub user_data: String,
+    pub meta_data: String,
+    pub network_config: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NetplanConfig{
+    pub version: u8,
+    pub ethernets: BTreeMap<String, NetplanEthernet>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NetplanEthernet{
+    #[serde(rename = "match")]
+    pub match_: NetplanMatch,
+    #[serde(rename = "set-name")]
+    pub set_name: String,
+    pub mtu: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dhcp4: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub addresses: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<NetplanRoute>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NetplanMatch{
+    pub macaddress: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NetplanRoute{
+    pub to: String,
+    pub via: String,
+    pub metric: u32,
+}
+
+impl CloudInit{
+    pub fn build(name: &str, instance: &InstanceRuntime, runtime: &Runtime) -> anyhow::Result<CloudInit>{
+        // the templates are yaml, html escaping would corrupt keys with = or ' in them
+        let mut reg = Handlebars::new();
+        reg.register_escape_fn(handlebars::no_escape);
+        let user = match &runtime.user_config{
+            Some(user_config) => {
+                let key = std::fs::read_to_string(&user_config.key_path)
+                    .with_context(|| format!("failed to read ssh key {}", user_config.key_path))?;
+                // quoted, a json string is a valid yaml scalar whatever the key comment contains
+                Some(json!({"user_name": serde_json::to_string(&user_config.user_name)?, "key": serde_json::to_string(key.trim())?}))
+            },
+            None => None,
+        };
+        let user_data = reg.render_template(USER_DATA, &json!({"hostname": name, "user": user}))?;
+        let meta_data = reg.render_template(META_DATA, &json!({"instance_id": instance.uuid, "hostname": name}))?;
+        let network_config = serde_yaml::to_string(&CloudInit::netplan(name, instance, runtime))?;
+        Ok(CloudInit{
+            user_data,
+            meta_data,
+            network_config,
+        })
+    }
+
+    pub fn netplan(name: &str, instance: &InstanceRuntime, runtime: &Runtime) -> NetplanConfig{
+        let mut ethernets = BTreeMap::new();
+        for (interface_name, interface) in &instance.interfaces{
+            let guest_name = guest_interface_name(name, interface_name);
+            let mut ethernet = NetplanEthernet{
+                match_: NetplanMatch{
+                    macaddress: interface.mac.to_string(),
+                },
+                set_name: guest_name.clone(),
+                mtu: interface.mtu,
+                dhcp4: None,
+                addresses: Vec::new(),
+                routes: Vec::new(),
+            };
+            let network = runtime.networks.get(&interface.network);
+            let subnet = network.and_then(|network| network.subnet());
+            let subnet6 = network.and_then(|network| network.subnet6());
+            // libvirt networks with a dhcp range hand out the address through a static host entry
+            let dhcp = match network.map(|network| &network.network_type){
+                Some(NetworkTypeRuntime::Unmanaged{..}) => false,
+                Some(NetworkTypeRuntime::Libvirt{dhcp_range, ..}) => dhcp_range.is_some(),
+                _ => true,
+            };
+            if dhcp{
+                ethernet.dhcp4 = Some(true);
+            } else if let (Some(address), Some(subnet)) = (interface.address, subnet){
+                ethernet.addresses.push(format!("{}/{}", address, subnet.prefix_len()));
+            }
+            // there is no router advertising on unmanaged bridges, ipv6 is configured statically as well
+            if let (Some(address6), Some(subnet6)) = (interface.address6, subnet6){
+                ethernet.addresses.push(format!("{}/{}", address6, subnet6.prefix_len()));
+            }
+            if let Some(network) = network{
+                for route_table in instance.route_tables.values(){
+                    for (destination, next_hops) in &route_table.routes{
+                        for (idx, next_hop) in next_hops.iter().enumerate(){
+                            if network.contains(*next_hop){
+                                ethernet.routes.push(NetplanRoute{
+                                    to: destination.to_string(),
+                                    via: next_hop.to_string(),
+                                    metric: 100 + idx as u32,
+                                });
+                            }
+                        }
+                    }
+                }
+            }
+            ethernets.insert(guest_name, ethernet);
+        }
+        NetplanConfig{
+            version: 2,
+            ethernets,
+        }
+    }
+}
+
+// interfaces are named <instance>_<name> in the topology, inside the guest only <name> is used
+fn guest_interface_name(instance: &str, interface: &str) -> String{
+    let name = interface.strip_prefix(&format!("{}_", instance)).unwrap_or(interface);
+    // linux limits interface names to 15 characters
+    name.chars().take(15).collect()
+}
+
+const META_DATA: &str = r#"instance-id: {{ instance_id }}
+local-hostname: {{ hostname }}
+"#;
+
+const USER_DATA: &str = r#"#cloud-config
+hostname: {{ hostname }}
+fqdn: {{ hostname }}
+package_update: false
+package_upgrade: false
+ssh_pwauth: true
+disable_root: false
+# grow the root partition and filesystem into a disk larger than the image
+growpart:
+  mode: auto
+  devices: ['/']
+resize_rootfs: true
+# apply a regenerated network-config on the next boot and when interfaces are hot-plugged,
+# not only on the first boot
+updates:
+  network:
+    when: ['boot', 'hotplug']
+{{#if user}}
+users:
+  - default
+  - name: ubuntu
+    shell: /bin/bash
+    sudo: ALL=(ALL) NOPASSWD:ALL
+    lock_passwd: false
+    ssh-authorized-keys:
+      - {{ user.key }}
+  - name: {{ user.user_name }}
+    shell: /bin/bash
+    sudo: ALL=(ALL) NOPASSWD:ALL
+    lock_passwd: false
+    ssh-authorized-keys:
+      - {{ user.key }}
+{{/if}}
+"#;
@@ -1,18 +1,31 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::path::Path;
 
 use serde::{Deserialize, Serialize};
-use crate::network::network::NetworkConfig;
-use crate::instance::instance::InstanceConfig;
+use crate::network::network::{BridgeType, Ipv6AddressMode, NetworkConfig, NetworkTypeConfig};
+use crate::instance::instance::{InstanceConfig, Memory};
 use crate::interface::interface::InterfaceConfig;
-use crate::route_table::route_table::RouteTableConfig;
+use crate::route_table::route_table::{RouteTableConfig, InstanceInterface};
+use crate::object::object::Object;
+use crate::xml::xml::XmlPatch;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config{
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    // libvirt connection uri of the hypervisor the topology is deployed on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect: Option<String>,
     pub user_config: Option<UserConfig>,
-    pub networks: HashMap<String, NetworkConfig>,
-    pub instances: HashMap<String, InstanceConfig>,
-    pub interfaces: HashMap<String, InterfaceConfig>,
-    pub route_tables: HashMap<String, RouteTableConfig>,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    // defaults for the domain xml of all instances
+    #[serde(default)]
+    pub domain: DomainTemplateConfig,
+    pub networks: BTreeMap<String, NetworkConfig>,
+    pub instances: BTreeMap<String, InstanceConfig>,
+    pub interfaces: BTreeMap<String, InterfaceConfig>,
+    pub route_tables: BTreeMap<String, RouteTableConfig>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -21,14 +34,134 @@ pub struct UserConfig{
     pub key_path: String,
 }
 
+// libvirt storage pool holding images, instance disks and seed isos
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct StorageConfig{
+    #[serde(default = "default_pool")]
+    pub pool: String,
+    // a dir pool is defined at this path if the pool doesn't exist yet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct DomainTemplateConfig{
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub patches: Vec<XmlPatch>,
+}
+
+pub const DEFAULT_POOL: &str = "default";
+
+fn default_pool() -> String{
+    DEFAULT_POOL.to_string()
+}
+
+impl Default for StorageConfig{
+    fn default() -> Self {
+        StorageConfig{
+            pool: default_pool(),
+            path: None,
+        }
+    }
+}
+
 impl Config{
     pub fn new(user_config: Option<UserConfig>) -> Config{
         Config{
+            name: None,
+            connect: None,
             user_config,
-            networks: HashMap::new(),
-            instances: HashMap::new(),
-            interfaces: HashMap::new(),
-            route_tables: HashMap::new(),
+            storage: StorageConfig::default(),
+            domain: DomainTemplateConfig::default(),
+            networks: BTreeMap::new(),
+            instances: BTreeMap::new(),
+            interfaces: BTreeMap::new(),
+            route_tables: BTreeMap::new(),
+        }
+    }
+
+    pub fn topology_name(&self) -> &str{
+        self.name.as_deref().unwrap_or("default")
+    }
+
+    // relative template paths are next to the config, like the state dir
+    pub fn resolve_templates(&mut self, config: &str){
+        let base = Path::new(config).parent().unwrap_or(Path::new(""));
+        let templates = std::iter::once(&mut self.domain.template)
+            .chain(self.instances.values_mut().map(|instance| &mut instance.domain.template));
+        for template in templates.flatten(){
+            *template = base.join(&*template).to_string_lossy().into_owned();
         }
     }
+
+    pub fn example() -> Config{
+        let mut config = Config::new(Some(UserConfig{
+            user_name: "ubuntu".to_string(),
+            key_path: "/home/ubuntu/.ssh/id_rsa.pub".to_string(),
+        }));
+
+        config.name = Some("example".to_string());
+
+        let network_config = NetworkConfig::new(NetworkTypeConfig::Managed { name: "default".to_string() });
+        config.add("mgmt", network_config);
+
+        let network_config = NetworkConfig::new(NetworkTypeConfig::Unmanaged {
+            subnet: Some("10.0.0.0/24".to_string()),
+            bridge_type: BridgeType::Linux,
+            gateway: None,
+            reserved: Vec::new(),
+            allocation_range: None,
+            subnet6: None,
+            gateway6: None,
+            address_mode6: Ipv6AddressMode::Sequential,
+        });
+        config.add("net1", network_config);
+
+        let network_config = NetworkConfig::new(NetworkTypeConfig::Unmanaged {
+            subnet: Some("10.0.1.0/24".to_string()),
+            bridge_type: BridgeType::Linux,
+            gateway: None,
+            reserved: Vec::new(),
+            allocation_range: None,
+            subnet6: None,
+            gateway6: None,
+            address_mode6: Ipv6AddressMode::Sequential,
+        });
+        config.add("net2", network_config);
+
+        let instance_config = InstanceConfig::new(1, Memory::from_gib(1), "/var/lib/libvirt/images/mantic-server-cloudimg-amd64.img");
+        config.add("vm1", instance_config);
+
+        let instance_config = InstanceConfig::new(1, Memory::from_gib(1), "/var/lib/libvirt/images/mantic-server-cloudimg-amd64.img");
+        config.add("vm2", instance_config);
+
+        let interface_config = InterfaceConfig::new(1500, "mgmt", "vm1");
+        config.add("vm1_eth0", interface_config);
+
+        let interface_config = InterfaceConfig::new(1500, "net1", "vm1");
+        config.add("vm1_eth1", interface_config);
+
+        let interface_config = InterfaceConfig::new(1500, "mgmt", "vm2");
+        config.add("vm2_eth0", interface_config);
+
+        let interface_config = InterfaceConfig::new(1500, "net1", "vm2");
+        config.add("vm2_eth1", interface_config);
+
+        let interface_config = InterfaceConfig::new(1500, "net2", "vm2");
+        config.add("vm2_eth2", interface_config);
+
+        let route_table_config = RouteTableConfig::new("vm1", {
+            let mut routes = BTreeMap::new();
+            routes.insert("net2".to_string(), vec![InstanceInterface{
+                instance: "vm2".to_string(),
+                interface: "vm2_eth1".to_string(),
+            }]);
+            routes
+        });
+        config.add("vm1_rt1", route_table_config);
+
+        config
+    }
 }
\ No newline at end of file
@@ -0,0 +1,418 @@
+use std::net::IpAddr;
+use anyhow::anyhow;
+use pnet::util::MacAddr;
+use serde::Serialize;
+use crate::instance::instance::{CpuConfig, CpuMode, DomainConfig, GraphicsType, InstanceRuntime, Memory};
+use crate::interface::interface::InterfaceRuntime;
+use crate::network::network::BridgeType;
+use crate::xml::xml::Element;
+
+// root ports left free on q35, interfaces added by an update are hot-plugged into them
+pub const HOTPLUG_PORTS: usize = 4;
+// usb controller, virtio-serial for the guest agent channel, balloon and rng
+const FIXED_PCIE_DEVICES: usize = 4;
+const OSINFO_NS: &str = "http://libosinfo.org/xmlns/libvirt/domain/1.0";
+const OSINFO_OS: &str = "http://ubuntu.com/ubuntu/23.10";
+
+// typed libvirt domain, turned into xml through Element
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct DomainDef{
+    pub domain_type: String,
+    pub name: String,
+    pub uuid: String,
+    pub memory: Memory,
+    pub vcpu: u16,
+    pub cpu: CpuConfig,
+    // page size
+    pub hugepages: Option<Memory>,
+    pub arch: String,
+    pub machine: String,
+    pub disks: Vec<Disk>,
+    pub controllers: Vec<Controller>,
+    pub interfaces: Vec<Interface>,
+    pub graphics: Option<Graphics>,
+    pub serial_log: Option<String>,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct Disk{
+    pub device: DiskDevice,
+    pub format: String,
+    // an empty cdrom drive has no source
+    pub source: Option<VolumeSource>,
+    pub target: String,
+    pub bus: String,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskDevice{
+    Disk,
+    Cdrom,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct VolumeSource{
+    pub pool: String,
+    pub volume: String,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct Controller{
+    pub controller_type: String,
+    pub model: String,
+    pub ports: Option<u16>,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct Interface{
+    pub mac: MacAddr,
+    pub source: InterfaceSource,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum InterfaceSource{
+    Network(String),
+    // taps on bridges are named after the interface and carry its mtu
+    Bridge{
+        bridge: String,
+        ovs: bool,
+        target: String,
+        mtu: u32,
+    },
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub struct Graphics{
+    pub graphics_type: GraphicsType,
+    pub listen: IpAddr,
+}
+
+pub struct DomainBuilder<'a>{
+    name: String,
+    instance: &'a InstanceRuntime,
+    domain_type: String,
+    disks: Vec<VolumeSource>,
+    cdrom: Option<Option<VolumeSource>>,
+    serial_log: Option<String>,
+}
+
+impl DomainDef{
+    pub fn builder<'a>(name: &str, instance: &'a InstanceRuntime) -> DomainBuilder<'a>{
+        DomainBuilder{
+            name: name.to_string(),
+            instance,
+            domain_type: "qemu".to_string(),
+            disks: Vec::new(),
+            cdrom: None,
+            serial_log: None,
+        }
+    }
+
+    pub fn is_x86(&self) -> bool{
+        is_x86(&self.arch)
+    }
+}
+
+impl<'a> DomainBuilder<'a>{
+    pub fn domain_type(mut self, domain_type: &str) -> Self{
+        self.domain_type = domain_type.to_string();
+        self
+    }
+
+    // virtio disks get vda, vdb, ... in the order they are added
+    pub fn disk(mut self, pool: &str, volume: &str) -> Self{
+        self.disks.push(VolumeSource::new(pool, volume));
+        self
+    }
+
+    pub fn cdrom(mut self, source: Option<VolumeSource>) -> Self{
+        self.cdrom = Some(source);
+        self
+    }
+
+    pub fn serial_log(mut self, path: &str) -> Self{
+        self.serial_log = Some(path.to_string());
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<DomainDef>{
+        let config = &self.instance.domain;
+        let mut disks: Vec<Disk> = self.disks.into_iter().enumerate().map(|(index, source)| Disk{
+            device: DiskDevice::Disk,
+            format: "qcow2".to_string(),
+            source: Some(source),
+            target: format!("vd{}", (b'a' + index as u8) as char),
+            bus: "virtio".to_string(),
+        }).collect();
+        let virtio_disks = disks.len();
+        let mut controllers = vec![Controller::new("usb", "qemu-xhci", Some(15))];
+        if let Some(source) = self.cdrom{
+            let cdrom = Disk::cdrom(config, source);
+            if cdrom.bus == "scsi"{
+                controllers.push(Controller::new("scsi", "virtio-scsi", None));
+            }
+            disks.push(cdrom);
+        }
+        let interfaces = self.instance.interfaces.iter()
+            .map(|(name, interface)| Interface::new(name, interface))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        // every pcie device sits on a root port of its own, the sata and vga controllers are part of q35
+        if is_q35(&config.machine){
+            controllers.push(Controller::new("pci", "pcie-root", None));
+            let ports = virtio_disks + FIXED_PCIE_DEVICES + interfaces.len() + HOTPLUG_PORTS;
+            controllers.extend((0..ports).map(|_| Controller::new("pci", "pcie-root-port", None)));
+        }
+        let graphics = match config.graphics{
+            GraphicsType::None => None,
+            graphics_type => Some(Graphics{graphics_type, listen: config.listen}),
+        };
+        Ok(DomainDef{
+            domain_type: self.domain_type,
+            name: self.name,
+            uuid: self.instance.uuid.clone(),
+            memory: self.instance.memory,
+            vcpu: self.instance.vcpu,
+            cpu: self.instance.cpu.clone(),
+            hugepages: self.instance.hugepages,
+            arch: config.arch.clone(),
+            machine: config.machine.clone(),
+            disks,
+            controllers,
+            interfaces,
+            graphics,
+            serial_log: self.serial_log,
+        })
+    }
+}
+
+impl VolumeSource{
+    pub fn new(pool: &str, volume: &str) -> VolumeSource{
+        VolumeSource{
+            pool: pool.to_string(),
+            volume: volume.to_string(),
+        }
+    }
+}
+
+impl Disk{
+    // sata on q35, ide on the i440fx pc machines and virtio-scsi everywhere else
+    pub fn cdrom(config: &DomainConfig, source: Option<VolumeSource>) -> Disk{
+        let (bus, target) = if is_q35(&config.machine){
+            ("sata", "sda")
+        } else if is_i440fx(&config.machine){
+            ("ide", "hda")
+        } else {
+            ("scsi", "sda")
+        };
+        Disk{
+            device: DiskDevice::Cdrom,
+            format: "raw".to_string(),
+            source,
+            target: target.to_string(),
+            bus: bus.to_string(),
+        }
+    }
+}
+
+impl Controller{
+    pub fn new(controller_type: &str, model: &str, ports: Option<u16>) -> Controller{
+        Controller{
+            controller_type: controller_type.to_string(),
+            model: model.to_string(),
+            ports,
+        }
+    }
+}
+
+impl Interface{
+    pub fn new(name: &str, interface: &InterfaceRuntime) -> anyhow::Result<Interface>{
+        let source = match (&interface.managed, &interface.bridge){
+            (Some(network), _) => InterfaceSource::Network(network.clone()),
+            (None, Some(bridge)) => InterfaceSource::Bridge{
+                bridge: bridge.name.clone(),
+                ovs: bridge.bridge_type == BridgeType::Ovs,
+                target: name.to_string(),
+                mtu: interface.mtu,
+            },
+            (None, None) => return Err(anyhow!("interface {} is neither on a libvirt network nor on a bridge", name)),
+        };
+        Ok(Interface{
+            mac: interface.mac,
+            source,
+        })
+    }
+}
+
+impl From<&Disk> for Element{
+    fn from(disk: &Disk) -> Self {
+        let (disk_type, device) = match (disk.device, &disk.source){
+            (DiskDevice::Disk, _) => ("volume", "disk"),
+            (DiskDevice::Cdrom, Some(_)) => ("volume", "cdrom"),
+            (DiskDevice::Cdrom, None) => ("file", "cdrom"),
+        };
+        let mut element = Element::new("disk").attr("type", disk_type).attr("device", device)
+            .child(Element::new("driver").attr("name", "qemu").attr("type", &disk.format));
+        if let Some(source) = &disk.source{
+            element = element.child(Element::new("source").attr("pool", &source.pool).attr("volume", &source.volume));
+        }
+        element = element.child(Element::new("target").attr("dev", &disk.target).attr("bus", &disk.bus));
+        if disk.device == DiskDevice::Cdrom{
+            element = element.child(Element::new("readonly"));
+        }
+        element
+    }
+}
+
+impl From<&Controller> for Element{
+    fn from(controller: &Controller) -> Self {
+        let element = Element::new("controller").attr("type", &controller.controller_type).attr("model", &controller.model);
+        match controller.ports{
+            Some(ports) => element.attr("ports", ports),
+            None => element,
+        }
+    }
+}
+
+impl From<&Interface> for Element{
+    fn from(interface: &Interface) -> Self {
+        let mac = Element::new("mac").attr("address", interface.mac);
+        let model = Element::new("model").attr("type", "virtio");
+        match &interface.source{
+            InterfaceSource::Network(network) => Element::new("interface").attr("type", "network")
+                .child(mac)
+                .child(Element::new("source").attr("network", network))
+                .child(model),
+            InterfaceSource::Bridge{bridge, ovs, target, mtu} => {
+                let mut element = Element::new("interface").attr("type", "bridge")
+                    .child(mac)
+                    .child(Element::new("source").attr("bridge", bridge));
+                if *ovs{
+                    element = element.child(Element::new("virtualport").attr("type", "openvswitch"));
+                }
+                element.child(Element::new("target").attr("dev", target))
+                    .child(model)
+                    .child(Element::new("mtu").attr("size", mtu))
+            },
+        }
+    }
+}
+
+impl From<&Graphics> for Element{
+    fn from(graphics: &Graphics) -> Self {
+        let graphics_type = match graphics.graphics_type{
+            GraphicsType::Spice => "spice",
+            _ => "vnc",
+        };
+        Element::new("graphics").attr("type", graphics_type).attr("port", -1).attr("listen", graphics.listen)
+    }
+}
+
+impl From<&CpuConfig> for Element{
+    fn from(cpu: &CpuConfig) -> Self {
+        let mut element = Element::new("cpu");
+        if let Some(mode) = cpu.mode(){
+            element = element.attr("mode", mode);
+            if mode == CpuMode::Custom{
+                element = element.attr("match", "exact");
+            }
+        }
+        if let Some(model) = &cpu.model{
+            element = element.child(Element::new("model").attr("fallback", "forbid").text(model));
+        }
+        if let Some(topology) = &cpu.topology{
+            element = element.child(Element::new("topology")
+                .attr("sockets", topology.sockets).attr("cores", topology.cores).attr("threads", topology.threads));
+        }
+        if !cpu.numa.is_empty(){
+            element = element.child(Element::new("numa").children(cpu.numa.iter().enumerate().map(|(id, cell)|
+                Element::new("cell").attr("id", id).attr("cpus", &cell.cpus).attr("memory", cell.memory.kib()).attr("unit", "KiB"))));
+        }
+        element
+    }
+}
+
+impl From<&DomainDef> for Element{
+    fn from(domain: &DomainDef) -> Self {
+        let metadata = Element::new("metadata").child(
+            Element::new("libosinfo:libosinfo").attr("xmlns:libosinfo", OSINFO_NS)
+                .child(Element::new("libosinfo:os").attr("id", OSINFO_OS)));
+        let os = Element::new("os")
+            .child(Element::new("type").attr("arch", &domain.arch).attr("machine", &domain.machine).text("hvm"))
+            .child(Element::new("boot").attr("dev", "hd"));
+        let mut features = Element::new("features").child(Element::new("acpi"));
+        let mut clock = Element::new("clock").attr("offset", "utc");
+        if domain.is_x86(){
+            features = features.child(Element::new("apic"));
+            clock = clock.children([
+                Element::new("timer").attr("name", "rtc").attr("tickpolicy", "catchup"),
+                Element::new("timer").attr("name", "pit").attr("tickpolicy", "delay"),
+                Element::new("timer").attr("name", "hpet").attr("present", "no"),
+            ]);
+        }
+        let pm = Element::new("pm")
+            .child(Element::new("suspend-to-mem").attr("enabled", "no"))
+            .child(Element::new("suspend-to-disk").attr("enabled", "no"));
+        let mut devices = Element::new("devices")
+            .child(Element::new("emulator").text(format!("/usr/bin/qemu-system-{}", domain.arch)))
+            .children(domain.disks.iter().map(Element::from))
+            .children(domain.controllers.iter().map(Element::from))
+            .children(domain.interfaces.iter().map(Element::from))
+            .child(Element::new("console").attr("type", "pty"))
+            .child(Element::new("channel").attr("type", "unix")
+                .child(Element::new("source").attr("mode", "bind"))
+                .child(Element::new("target").attr("type", "virtio").attr("name", "org.qemu.guest_agent.0")));
+        // tablet and video only matter to a graphical console
+        if let Some(graphics) = &domain.graphics{
+            let video = if domain.is_x86(){ "vga" } else { "virtio" };
+            devices = devices.child(Element::new("input").attr("type", "tablet").attr("bus", "usb"))
+                .child(Element::from(graphics))
+                .child(Element::new("video").child(Element::new("model").attr("type", video)));
+        }
+        devices = devices.child(Element::new("memballoon").attr("model", "virtio"))
+            .child(Element::new("rng").attr("model", "virtio")
+                .child(Element::new("backend").attr("model", "random").text("/dev/urandom")));
+        if let Some(serial_log) = &domain.serial_log{
+            devices = devices.child(Element::new("serial").attr("type", "file")
+                .child(Element::new("source").attr("path", serial_log))
+                .child(Element::new("target").attr("port", 0)));
+        }
+        let mut element = Element::new("domain").attr("type", &domain.domain_type)
+            .child(Element::new("name").text(&domain.name))
+            .child(Element::new("uuid").text(&domain.uuid))
+            .child(metadata)
+            .child(Element::new("memory").attr("unit", "KiB").text(domain.memory.kib()))
+            .child(Element::new("currentMemory").attr("unit", "KiB").text(domain.memory.kib()));
+        if let Some(page_size) = domain.hugepages{
+            element = element.child(Element::new("memoryBacking").child(Element::new("hugepages")
+                .child(Element::new("page").attr("size", page_size.kib()).attr("unit", "KiB"))));
+        }
+        element = element.child(Element::new("vcpu").text(domain.vcpu));
+        if !domain.cpu.pinning.is_empty(){
+            element = element.child(Element::new("cputune").children(domain.cpu.pinning.iter().map(|(vcpu, cpuset)|
+                Element::new("vcpupin").attr("vcpu", vcpu).attr("cpuset", cpuset))));
+        }
+        element = element.child(os).child(features);
+        // pinning alone lives in cputune
+        if domain.cpu.mode().is_some() || domain.cpu.topology.is_some() || !domain.cpu.numa.is_empty(){
+            element = element.child(Element::from(&domain.cpu));
+        }
+        element.child(clock)
+            .child(pm)
+            .child(devices)
+    }
+}
+
+fn is_x86(arch: &str) -> bool{
+    matches!(arch, "x86_64" | "i686")
+}
+
+// q35 or a versioned pc-q35-* machine
+pub fn is_q35(machine: &str) -> bool{
+    machine == "q35" || machine.starts_with("pc-q35")
+}
+
+fn is_i440fx(machine: &str) -> bool{
+    machine == "pc" || machine.starts_with("pc-i440fx")
+}
@@ -1,24 +1,324 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
 
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
 use crate::interface::interface::InterfaceRuntime;
 use crate::object::object::Object;
 use crate::config::config::Config;
 use crate::route_table::route_table::RouteTableRuntime;
+use crate::xml::xml::XmlPatch;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct InstanceConfig{
     pub vcpu: u16,
-    pub memory: u16,
+    pub memory: Memory,
     pub image: String,
+    #[serde(default = "default_persistent")]
+    pub persistent: bool,
+    #[serde(default)]
+    pub autostart: bool,
+    #[serde(default)]
+    pub disk: DiskConfig,
+    #[serde(default)]
+    pub domain: DomainConfig,
+    #[serde(default)]
+    pub cpu: CpuConfig,
+    // page size of the hugepages backing the guest memory, like 2MiB or 1GiB
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hugepages: Option<Memory>,
+}
+
+// amount of memory like 4GiB or 512MiB, the unit is required
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(try_from = "String", into = "String")]
+pub struct Memory{
+    kib: u64,
+}
+
+// state written before memory had units holds bare numbers of GiB
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LegacyMemory{
+    Gib(u64),
+    Memory(Memory),
+}
+
+const MEMORY_UNITS: [(&str, u64); 4] = [("TiB", 1 << 30), ("GiB", 1 << 20), ("MiB", 1 << 10), ("KiB", 1)];
+
+impl Memory{
+    pub fn from_kib(kib: u64) -> Memory{
+        Memory{kib}
+    }
+
+    pub fn from_mib(mib: u64) -> Memory{
+        Memory{kib: mib << 10}
+    }
+
+    pub fn from_gib(gib: u64) -> Memory{
+        Memory{kib: gib << 20}
+    }
+
+    pub fn kib(&self) -> u64{
+        self.kib
+    }
+}
+
+impl FromStr for Memory{
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+        let (number, unit) = (&value[..split], value[split..].trim());
+        let number: u64 = number.parse().map_err(|_| format!("'{}' doesn't start with a number", value))?;
+        let (_, factor) = MEMORY_UNITS.iter().find(|(name, _)| *name == unit)
+            .ok_or(format!("'{}' needs one of the units KiB, MiB, GiB or TiB", value))?;
+        let kib = number.checked_mul(*factor).ok_or(format!("'{}' is too large", value))?;
+        Ok(Memory{kib})
+    }
+}
+
+impl TryFrom<String> for Memory{
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+fn legacy_memory<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Memory, D::Error>{
+    match LegacyMemory::deserialize(deserializer)?{
+        LegacyMemory::Gib(gib) => gib.checked_mul(1 << 20).map(Memory::from_kib).ok_or(de::Error::custom(format!("{}GiB is too large", gib))),
+        LegacyMemory::Memory(memory) => Ok(memory),
+    }
+}
+
+impl From<Memory> for String{
+    fn from(memory: Memory) -> Self {
+        memory.to_string()
+    }
+}
+
+// in the largest unit that divides it
+impl fmt::Display for Memory{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (unit, factor) = MEMORY_UNITS.iter().find(|(_, factor)| self.kib.is_multiple_of(*factor)).unwrap_or(&("KiB", 1));
+        write!(f, "{}{}", self.kib / factor, unit)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct CpuConfig{
+    // the hypervisor default if not set, custom if only a model is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<CpuMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    // sockets * cores * threads has to be the vcpu count
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topology: Option<CpuTopology>,
+    // host cpus a vcpu may run on, in libvirt cpuset syntax like 2-5,^3
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pinning: BTreeMap<u16, String>,
+    // guest numa cells, together they hold all vcpus and all memory
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub numa: Vec<NumaCell>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CpuMode{
+    HostPassthrough,
+    HostModel,
+    Maximum,
+    Custom,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct CpuTopology{
+    pub sockets: u16,
+    pub cores: u16,
+    pub threads: u16,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct NumaCell{
+    // vcpus of the cell in cpuset syntax
+    pub cpus: String,
+    pub memory: Memory,
+}
+
+impl CpuConfig{
+    pub fn mode(&self) -> Option<CpuMode>{
+        self.mode.or(self.model.as_ref().map(|_| CpuMode::Custom))
+    }
+
+    pub fn is_default(&self) -> bool{
+        *self == CpuConfig::default()
+    }
+}
+
+impl fmt::Display for CpuMode{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mode = match self{
+            CpuMode::HostPassthrough => "host-passthrough",
+            CpuMode::HostModel => "host-model",
+            CpuMode::Maximum => "maximum",
+            CpuMode::Custom => "custom",
+        };
+        write!(f, "{}", mode)
+    }
+}
+
+impl fmt::Display for CpuConfig{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        match (self.mode(), &self.model){
+            (Some(mode), Some(model)) => parts.push(format!("{} {}", mode, model)),
+            (Some(mode), None) => parts.push(mode.to_string()),
+            _ => {},
+        }
+        if let Some(topology) = &self.topology{
+            parts.push(format!("{} sockets {} cores {} threads", topology.sockets, topology.cores, topology.threads));
+        }
+        if !self.pinning.is_empty(){
+            let pins: Vec<String> = self.pinning.iter().map(|(vcpu, cpuset)| format!("{}:{}", vcpu, cpuset)).collect();
+            parts.push(format!("pinned {}", pins.join(" ")));
+        }
+        if !self.numa.is_empty(){
+            let cells: Vec<String> = self.numa.iter().map(|cell| format!("{}:{}", cell.cpus, cell.memory)).collect();
+            parts.push(format!("numa {}", cells.join(" ")));
+        }
+        if parts.is_empty(){
+            parts.push("default".to_string());
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub struct DiskConfig{
+    #[serde(default)]
+    pub mode: DiskMode,
+    // virtual size in GiB, the image size if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskMode{
+    // qcow2 overlay backed by the image, the image must not change while instances use it
+    #[default]
+    Overlay,
+    // full qcow2 copy of the image
+    Clone,
+}
+
+impl fmt::Display for DiskConfig{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mode = match self.mode{
+            DiskMode::Overlay => "overlay",
+            DiskMode::Clone => "clone",
+        };
+        match self.size{
+            Some(size) => write!(f, "{} {}GiB", mode, size),
+            None => write!(f, "{}", mode),
+        }
+    }
+}
+
+// virtual hardware of the domain
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DomainConfig{
+    #[serde(default = "default_arch")]
+    pub arch: String,
+    #[serde(default = "default_machine")]
+    pub machine: String,
+    #[serde(default)]
+    pub graphics: GraphicsType,
+    // address vnc or spice listen on
+    #[serde(default = "default_listen")]
+    pub listen: IpAddr,
+    // handlebars template used instead of the built-in domain xml, relative to the config file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    // applied in order to the built-in or templated domain xml
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub patches: Vec<XmlPatch>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum GraphicsType{
+    #[default]
+    Vnc,
+    Spice,
+    None,
+}
+
+fn default_arch() -> String{
+    "x86_64".to_string()
+}
+
+fn default_machine() -> String{
+    "q35".to_string()
+}
+
+fn default_listen() -> IpAddr{
+    IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+}
+
+impl Default for DomainConfig{
+    fn default() -> Self {
+        DomainConfig{
+            arch: default_arch(),
+            machine: default_machine(),
+            graphics: GraphicsType::default(),
+            listen: default_listen(),
+            template: None,
+            patches: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for DomainConfig{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.arch, self.machine)?;
+        match self.graphics{
+            GraphicsType::Vnc => write!(f, ", vnc on {}", self.listen),
+            GraphicsType::Spice => write!(f, ", spice on {}", self.listen),
+            GraphicsType::None => write!(f, ", no graphics"),
+        }?;
+        if let Some(template) = &self.template{
+            write!(f, ", template {}", template)?;
+        }
+        for patch in &self.patches{
+            write!(f, ", {}", patch)?;
+        }
+        Ok(())
+    }
+}
+
+fn default_persistent() -> bool{
+    true
 }
 
 impl InstanceConfig{
-    pub fn new(vcpu: u16, memory: u16, image: &str) -> InstanceConfig{
+    pub fn new(vcpu: u16, memory: Memory, image: &str) -> InstanceConfig{
         InstanceConfig{
             vcpu,
             memory,
             image: image.to_string(),
+            persistent: default_persistent(),
+            autostart: false,
+            disk: DiskConfig::default(),
+            domain: DomainConfig::default(),
+            cpu: CpuConfig::default(),
+            hugepages: None,
         }
     }
 }
@@ -37,36 +337,92 @@ impl <'a>Object<'a, InstanceConfig> for Config {
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct InstanceRuntime{
+    pub uuid: String,
     pub vcpu: u16,
-    pub memory: u16,
+    #[serde(deserialize_with = "legacy_memory")]
+    pub memory: Memory,
     pub image: String,
-    pub interfaces: HashMap<String, InterfaceRuntime>,
-    pub route_tables: HashMap<String, RouteTableRuntime>,
+    pub persistent: bool,
+    pub autostart: bool,
+    // state written before disks were configurable holds no disk
+    #[serde(default)]
+    pub disk: DiskConfig,
+    #[serde(default)]
+    pub domain: DomainConfig,
+    #[serde(default)]
+    pub cpu: CpuConfig,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hugepages: Option<Memory>,
+    // hash of the template file, a changed file redefines the domain like a changed path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template_hash: Option<String>,
+    pub interfaces: BTreeMap<String, InterfaceRuntime>,
+    pub route_tables: BTreeMap<String, RouteTableRuntime>,
 }
 
-impl From<InstanceConfig> for InstanceRuntime{
-    fn from(config: InstanceConfig) -> Self {
+impl InstanceRuntime{
+    pub fn new(topology: &str, name: &str, config: InstanceConfig) -> Self {
+        let uuid = instance_uuid(topology, name);
         let vcpu = config.vcpu;
         let memory = config.memory;
         let image = config.image;
-        let interfaces = HashMap::new();
-        let route_tables = HashMap::new();
+        let persistent = config.persistent;
+        let autostart = config.autostart;
+        let disk = config.disk;
+        let domain = config.domain;
+        let cpu = config.cpu;
+        let hugepages = config.hugepages;
+        let template_hash = domain.template.as_deref().and_then(template_hash);
+        let interfaces = BTreeMap::new();
+        let route_tables = BTreeMap::new();
         InstanceRuntime{
+            uuid,
             vcpu,
             memory,
             image,
+            persistent,
+            autostart,
+            disk,
+            domain,
+            cpu,
+            hugepages,
+            template_hash,
             interfaces,
             route_tables,
         }
     }
 }
 
-impl From<&Config> for HashMap<String,InstanceRuntime>{
+impl From<&Config> for BTreeMap<String,InstanceRuntime>{
     fn from(config: &Config) -> Self {
-        let mut instances = HashMap::new();
+        let mut instances = BTreeMap::new();
         for (name, instance) in &config.instances{
-            instances.insert(name.to_string(), InstanceRuntime::from(instance.clone()));
+            let mut instance = instance.clone();
+            // the topology template is the fallback, its patches come before those of the instance
+            if instance.domain.template.is_none(){
+                instance.domain.template = config.domain.template.clone();
+            }
+            instance.domain.patches = config.domain.patches.iter().chain(&instance.domain.patches).cloned().collect();
+            instances.insert(name.to_string(), InstanceRuntime::new(config.topology_name(), name, instance));
         }
         instances
     }
+}
+
+// a template that can't be read has no hash, rendering it reports the error
+fn template_hash(path: &str) -> Option<String>{
+    let content = std::fs::read(path).ok()?;
+    Some(Sha256::digest(&content).iter().take(8).map(|b| format!("{:02x}", b)).collect())
+}
+
+// name based uuid, so a domain keeps its identity when the topology is redeployed. it is a
+// version 8 uuid, version 5 would have to be sha-1 of a namespace and the name
+fn instance_uuid(topology: &str, name: &str) -> String{
+    let digest = Sha256::digest(format!("{}/{}", topology, name).as_bytes());
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    bytes[6] = (bytes[6] & 0x0f) | 0x80;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
 }
\ No newline at end of file
@@ -1,17 +1,26 @@
-use std::collections::HashMap;
-use std::net::Ipv4Addr;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
+use pnet::util::MacAddr;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use crate::instance::instance::InstanceRuntime;
 use crate::object::object::Object;
 use crate::config::config::Config;
-use crate::network::network::{NetworkRuntime, NetworkTypeRuntime};
+use crate::network::network::{BridgeRuntime, NetworkRuntime, NetworkTypeRuntime};
+use crate::runtime::runtime::RuntimeError;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct InterfaceConfig{
     pub mtu: u32,
     pub network: String,
     pub instance: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<MacAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<Ipv4Addr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address6: Option<Ipv6Addr>,
 }
 
 impl InterfaceConfig{
@@ -20,6 +29,9 @@ impl InterfaceConfig{
             mtu,
             network: network.to_string(),
             instance: instance.to_string(),
+            mac: None,
+            address: None,
+            address6: None,
         }
     }
 }
@@ -36,39 +48,133 @@ impl <'a>Object<'a, InterfaceConfig> for Config {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct InterfaceRuntime{
+    pub network: String,
     pub mtu: u32,
+    pub mac: MacAddr,
     pub address: Option<Ipv4Addr>,
+    pub address6: Option<Ipv6Addr>,
     pub managed: Option<String>,
+    pub bridge: Option<BridgeRuntime>,
 }
 
 impl InterfaceRuntime{
-    pub fn configure(config: &Config, networks: &mut HashMap<String, NetworkRuntime>, instances: &mut HashMap<String, InstanceRuntime>){
+    pub fn configure(config: &Config, networks: &mut BTreeMap<String, NetworkRuntime>, instances: &mut BTreeMap<String, InstanceRuntime>) -> Result<(), RuntimeError>{
+        // pinned addresses are claimed first, so the automatic assignment can't hand them out
+        for (name, interface) in &config.interfaces{
+            let pinned = interface.address.map(IpAddr::V4).into_iter().chain(interface.address6.map(IpAddr::V6));
+            for address in pinned{
+                let network = networks.get_mut(&interface.network).ok_or_else(|| RuntimeError::UnknownReference{
+                    kind: "network",
+                    name: interface.network.clone(),
+                    referenced_by: format!("interface {}", name),
+                })?;
+                if !network.is_usable(address){
+                    return Err(RuntimeError::AddressOutOfRange{
+                        network: interface.network.clone(),
+                        address,
+                        interface: name.clone(),
+                    });
+                }
+                if !network.claim_address(address){
+                    return Err(RuntimeError::AddressConflict{
+                        network: interface.network.clone(),
+                        address,
+                        interface: name.clone(),
+                    });
+                }
+            }
+        }
+        // generated macs only have 24 bits of the hash, they can collide with each other or a pinned one
+        let mut macs: BTreeMap<MacAddr, &String> = BTreeMap::new();
         for (name, interface) in &config.interfaces{
-            let network = networks.get_mut(&interface.network).unwrap();
-            let instance = instances.get_mut(&interface.instance).unwrap();
+            let network = networks.get_mut(&interface.network).ok_or_else(|| RuntimeError::UnknownReference{
+                kind: "network",
+                name: interface.network.clone(),
+                referenced_by: format!("interface {}", name),
+            })?;
+            let instance = instances.get_mut(&interface.instance).ok_or_else(|| RuntimeError::UnknownReference{
+                kind: "instance",
+                name: interface.instance.clone(),
+                referenced_by: format!("interface {}", name),
+            })?;
+            let exhausted = |subnet: ipnet::IpNet| RuntimeError::SubnetExhausted{
+                network: interface.network.clone(),
+                subnet,
+            };
+            let mac = interface.mac.unwrap_or_else(|| interface_mac(config.topology_name(), &interface.instance, name));
+            if let Some(other) = macs.insert(mac, name){
+                return Err(RuntimeError::MacConflict{
+                    mac,
+                    interface: name.clone(),
+                    other: other.clone(),
+                });
+            }
             match &network.network_type{
-                NetworkTypeRuntime::Unmanaged{subnet: _, addresses: _, gateway: _} => {
-                    let address = network.assign_address().unwrap();
+                NetworkTypeRuntime::Unmanaged{bridge, ..} => {
+                    let bridge = bridge.clone();
+                    let address = match (interface.address, network.subnet()){
+                        (Some(address), _) => Some(address),
+                        (None, Some(subnet)) => Some(network.assign_address().ok_or_else(|| exhausted(subnet.into()))?),
+                        (None, None) => None,
+                    };
+                    let address6 = match (interface.address6, network.subnet6()){
+                        (Some(address6), _) => Some(address6),
+                        (None, Some(subnet)) => Some(network.assign_address6(mac).ok_or_else(|| exhausted(subnet.into()))?),
+                        (None, None) => None,
+                    };
                     let mtu = interface.mtu;
                     let interface = InterfaceRuntime{
+                        network: interface.network.clone(),
                         mtu,
-                        address: Some(address),
+                        mac,
+                        address,
+                        address6,
                         managed: None,
+                        bridge: Some(bridge),
                     };
                     instance.interfaces.insert(name.clone(), interface);
                 },
-                NetworkTypeRuntime::Managed{name} => {
+                NetworkTypeRuntime::Libvirt{name: managed, ..} => {
+                    let managed = managed.clone();
+                    let address = match (interface.address, network.subnet()){
+                        (Some(address), _) => address,
+                        (None, subnet) => network.assign_address().ok_or_else(|| exhausted(subnet.unwrap_or_default().into()))?,
+                    };
+                    let mtu = interface.mtu;
+                    let interface = InterfaceRuntime{
+                        network: interface.network.clone(),
+                        mtu,
+                        mac,
+                        address: Some(address),
+                        address6: None,
+                        managed: Some(managed),
+                        bridge: None,
+                    };
+                    instance.interfaces.insert(name.clone(), interface);
+                },
+                NetworkTypeRuntime::Managed{name: managed} => {
                     let mtu = interface.mtu;
                     let interface = InterfaceRuntime{
+                        network: interface.network.clone(),
                         mtu,
+                        mac,
                         address: None,
-                        managed: Some(name.clone()),
+                        address6: None,
+                        managed: Some(managed.clone()),
+                        bridge: None,
                     };
                     instance.interfaces.insert(name.clone(), interface);
                 },
             }
         }
+        Ok(())
     }
+}
+
+// locally administered mac in the qemu/kvm 52:54:00 range, stable across redeployments
+fn interface_mac(topology: &str, instance: &str, name: &str) -> MacAddr{
+    let digest = Sha256::digest(format!("{}/{}/{}", topology, instance, name).as_bytes());
+    MacAddr::new(0x52, 0x54, 0x00, digest[0], digest[1], digest[2])
 }
\ No newline at end of file
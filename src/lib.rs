@@ -0,0 +1,16 @@
+pub mod backend;
+pub mod cli;
+pub mod cloud_init;
+pub mod config;
+pub mod domain;
+pub mod network;
+pub mod instance;
+pub mod interface;
+pub mod route_table;
+pub mod object;
+pub mod plan;
+pub mod runtime;
+pub mod state;
+pub mod validation;
+pub mod virt_manager;
+pub mod xml;
@@ -1,25 +1,120 @@
-use std::collections::{BTreeMap, HashMap};
-use std::net::Ipv4Addr;
-use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use pnet::util::MacAddr;
+use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+use serde_yaml::value::{Tag, TaggedValue};
+use sha2::{Digest, Sha256};
 use crate::object::object::Object;
 use crate::config::config::Config;
+use crate::runtime::runtime::RuntimeError;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct NetworkConfig{
     pub network_type: NetworkTypeConfig,
 }
 
+// written without a tag, the keys tell the type apart, see the Deserialize impl below
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
-#[serde(untagged)]
+#[serde(remote = "Self", rename_all = "lowercase", deny_unknown_fields)]
 pub enum NetworkTypeConfig{
     Managed{
         name: String,
     },
-    Unmanaged{
+    Libvirt{
+        mode: NetworkMode,
         subnet: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dhcp_range: Option<AddressRange>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        domain: Option<String>,
+    },
+    // ipv4 only, ipv6 only or dual-stack, depending on which subnets are set
+    Unmanaged{
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        subnet: Option<String>,
+        #[serde(default)]
+        bridge_type: BridgeType,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        gateway: Option<Ipv4Addr>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        reserved: Vec<AddressRange>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        allocation_range: Option<AddressRange>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        subnet6: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        gateway6: Option<Ipv6Addr>,
+        #[serde(default)]
+        address_mode6: Ipv6AddressMode,
     },
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Ipv6AddressMode{
+    // next free address after the gateway
+    #[default]
+    Sequential,
+    // interface id derived from the mac, requires a /64
+    Eui64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BridgeType{
+    #[default]
+    Linux,
+    Ovs,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkMode{
+    Nat,
+    Route,
+    Isolated,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct AddressRange{
+    pub start: Ipv4Addr,
+    pub end: Ipv4Addr,
+}
+
+impl AddressRange{
+    pub fn contains(&self, address: &Ipv4Addr) -> bool{
+        self.start <= *address && *address <= self.end
+    }
+}
+
+// a name makes a managed network and a mode a libvirt network, everything else is a bridge.
+// each type rejects unknown keys, so a misspelled key is an error instead of another type
+impl<'de> Deserialize<'de> for NetworkTypeConfig{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = serde_yaml::Value::deserialize(deserializer)?;
+        let network_type = if value.get("name").is_some(){
+            "managed"
+        } else if value.get("mode").is_some(){
+            "libvirt"
+        } else {
+            "unmanaged"
+        };
+        let tagged = TaggedValue{tag: Tag::new(network_type), value};
+        NetworkTypeConfig::deserialize(serde_yaml::Value::Tagged(Box::new(tagged)))
+            .map_err(|e| de::Error::custom(format!("{} network: {}", network_type, e)))
+    }
+}
+
+impl Serialize for NetworkTypeConfig{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match NetworkTypeConfig::serialize(self, serde_yaml::value::Serializer).map_err(ser::Error::custom)?{
+            serde_yaml::Value::Tagged(tagged) => tagged.value.serialize(serializer),
+            value => value.serialize(serializer),
+        }
+    }
+}
+
 impl NetworkConfig{
     pub fn new(network_type: NetworkTypeConfig) -> NetworkConfig{
         NetworkConfig{
@@ -48,51 +143,270 @@ pub struct NetworkRuntime{
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(untagged)]
 pub enum NetworkTypeRuntime{
-    Managed{
+    // has to come before Managed, untagged deserialization picks the first variant that fits
+    Libvirt{
         name: String,
-    },
-    Unmanaged{
+        mode: NetworkMode,
         subnet: ipnet::Ipv4Net,
         addresses: BTreeMap<u32, Ipv4Addr>,
         gateway: Ipv4Addr,
+        dhcp_range: Option<AddressRange>,
+        domain: Option<String>,
+    },
+    Managed{
+        name: String,
+    },
+    Unmanaged{
+        ipv4: Option<Ipv4Runtime>,
+        ipv6: Option<Ipv6Runtime>,
+        bridge: BridgeRuntime,
     },
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Ipv4Runtime{
+    pub subnet: ipnet::Ipv4Net,
+    pub addresses: BTreeMap<u32, Ipv4Addr>,
+    pub gateway: Ipv4Addr,
+    pub reserved: Vec<AddressRange>,
+    pub allocation_range: AddressRange,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Ipv6Runtime{
+    pub subnet: ipnet::Ipv6Net,
+    #[serde(with = "ipv6_addresses")]
+    pub addresses: BTreeMap<u128, Ipv6Addr>,
+    pub gateway: Ipv6Addr,
+    pub mode: Ipv6AddressMode,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BridgeRuntime{
+    pub name: String,
+    pub bridge_type: BridgeType,
+}
+
+impl BridgeRuntime{
+    pub fn new(topology: &str, network: &str, bridge_type: BridgeType) -> BridgeRuntime{
+        BridgeRuntime{
+            name: bridge_name(topology, network),
+            bridge_type,
+        }
+    }
+}
+
+// vr<topology hash>-<network>, so equally named networks of two topologies get their own
+// bridge. linux limits interface names to 15 characters, long network names fall back to a hash
+fn bridge_name(topology: &str, network: &str) -> String{
+    let topology_hash: String = Sha256::digest(topology.as_bytes()).iter().take(2).map(|b| format!("{:02x}", b)).collect();
+    let name = format!("vr{}-{}", topology_hash, network);
+    if name.len() <= 15{
+        return name;
+    }
+    let digest = Sha256::digest(format!("{}/{}", topology, network).as_bytes());
+    let hash: String = digest.iter().take(6).map(|b| format!("{:02x}", b)).collect();
+    format!("vr-{}", hash)
+}
+
 impl NetworkRuntime{
     pub fn assign_address(&mut self) -> Option<Ipv4Addr>{
         match self.network_type{
-            NetworkTypeRuntime::Unmanaged{subnet, ref mut addresses, gateway} => {
-                let mut first_address = u32::from_be_bytes(subnet.network().octets());
-                first_address += 1;
-                loop {
-                    if !addresses.contains_key(&first_address){
-                        let address = Ipv4Addr::from(first_address);
-                        addresses.insert(first_address, address);
+            NetworkTypeRuntime::Unmanaged{ipv4: Some(ref mut ipv4), ..} => {
+                next_free_address(&mut ipv4.addresses, ipv4.allocation_range, &ipv4.reserved)
+            },
+            NetworkTypeRuntime::Libvirt{subnet, ref mut addresses, ..} => {
+                next_free_address(addresses, host_range(&subnet)?, &[])
+            },
+            _ => None,
+        }
+
+    }
+
+    pub fn assign_address6(&mut self, mac: MacAddr) -> Option<Ipv6Addr>{
+        let ipv6 = match self.network_type{
+            NetworkTypeRuntime::Unmanaged{ipv6: Some(ref mut ipv6), ..} => ipv6,
+            _ => return None,
+        };
+        match ipv6.mode{
+            Ipv6AddressMode::Sequential => {
+                let first = u128::from(ipv6.subnet.network()).checked_add(1)?;
+                let last = u128::from(ipv6.subnet.broadcast());
+                for candidate in first..=last{
+                    if let Entry::Vacant(entry) = ipv6.addresses.entry(candidate){
+                        let address = Ipv6Addr::from(candidate);
+                        entry.insert(address);
                         return Some(address);
                     }
-                    first_address += 1;
                 }
+                None
+            },
+            Ipv6AddressMode::Eui64 => {
+                let address = eui64_address(&ipv6.subnet, mac);
+                match ipv6.addresses.entry(u128::from(address)){
+                    Entry::Vacant(entry) => Some(*entry.insert(address)),
+                    Entry::Occupied(_) => None,
+                }
+            },
+        }
+    }
+
+    // marks a statically configured address as used, returns false if it was already taken
+    pub fn claim_address(&mut self, address: IpAddr) -> bool{
+        match (&mut self.network_type, address){
+            (NetworkTypeRuntime::Unmanaged{ipv4: Some(Ipv4Runtime{addresses, ..}), ..}, IpAddr::V4(address)) |
+            (NetworkTypeRuntime::Libvirt{addresses, ..}, IpAddr::V4(address)) => {
+                addresses.insert(u32::from(address), address).is_none()
+            },
+            (NetworkTypeRuntime::Unmanaged{ipv6: Some(Ipv6Runtime{addresses, ..}), ..}, IpAddr::V6(address)) => {
+                addresses.insert(u128::from(address), address).is_none()
+            },
+            _ => false,
+        }
+    }
+
+    // whether the address can be given to an interface, network and broadcast addresses can't
+    pub fn is_usable(&self, address: IpAddr) -> bool{
+        match address{
+            IpAddr::V4(address) => self.subnet().is_some_and(|subnet| {
+                subnet.contains(&address) && address != subnet.network() && address != subnet.broadcast()
+            }),
+            IpAddr::V6(address) => self.subnet6().is_some_and(|subnet| {
+                subnet.contains(&address) && address != subnet.network()
+            }),
+        }
+    }
+
+    // whether the automatic assignment could hand out the address
+    pub fn is_allocatable(&self, address: IpAddr) -> bool{
+        match (&self.network_type, address){
+            (NetworkTypeRuntime::Unmanaged{ipv4: Some(ipv4), ..}, IpAddr::V4(address)) => {
+                ipv4.allocation_range.contains(&address) && !ipv4.reserved.iter().any(|reserved| reserved.contains(&address))
             },
+            _ => self.is_usable(address),
+        }
+    }
+
+    // whether the address is on the link of this network
+    pub fn contains(&self, address: IpAddr) -> bool{
+        match address{
+            IpAddr::V4(address) => self.subnet().is_some_and(|subnet| subnet.contains(&address)),
+            IpAddr::V6(address) => self.subnet6().is_some_and(|subnet| subnet.contains(&address)),
+        }
+    }
+
+    pub fn subnet(&self) -> Option<ipnet::Ipv4Net>{
+        match &self.network_type{
+            NetworkTypeRuntime::Unmanaged{ipv4, ..} => ipv4.as_ref().map(|ipv4| ipv4.subnet),
+            NetworkTypeRuntime::Libvirt{subnet, ..} => Some(*subnet),
+            NetworkTypeRuntime::Managed{..} => None,
+        }
+    }
+
+    pub fn subnet6(&self) -> Option<ipnet::Ipv6Net>{
+        match &self.network_type{
+            NetworkTypeRuntime::Unmanaged{ipv6, ..} => ipv6.as_ref().map(|ipv6| ipv6.subnet),
             _ => None,
         }
+    }
 
+    pub fn gateway(&self) -> Option<Ipv4Addr>{
+        match &self.network_type{
+            NetworkTypeRuntime::Unmanaged{ipv4, ..} => ipv4.as_ref().map(|ipv4| ipv4.gateway),
+            NetworkTypeRuntime::Libvirt{gateway, ..} => Some(*gateway),
+            NetworkTypeRuntime::Managed{..} => None,
+        }
+    }
+
+    pub fn gateway6(&self) -> Option<Ipv6Addr>{
+        match &self.network_type{
+            NetworkTypeRuntime::Unmanaged{ipv6, ..} => ipv6.as_ref().map(|ipv6| ipv6.gateway),
+            _ => None,
+        }
     }
 }
 
-impl From<NetworkConfig> for NetworkRuntime{
-    fn from(config: NetworkConfig) -> Self {
-        match config.network_type{
-            NetworkTypeConfig::Unmanaged { subnet } => {
-                let subnet: ipnet::Ipv4Net = subnet.parse().unwrap();
-                let first_address = u32::from_be_bytes(subnet.network().octets()) + 1;
-                let gateway = Ipv4Addr::from(first_address);
-                let mut addresses = BTreeMap::new();
-                addresses.insert(first_address, gateway);
+impl NetworkRuntime{
+    pub fn new(topology: &str, name: &str, config: NetworkConfig) -> Result<Self, RuntimeError> {
+        let invalid = |subnet: String| RuntimeError::InvalidCidr{
+            network: name.to_string(),
+            subnet,
+        };
+        // a subnet without room for a gateway and a host is as unusable as one that doesn't parse
+        let parse_subnet = |subnet: String| match subnet.parse::<ipnet::Ipv4Net>().map(|subnet| subnet.trunc()){
+            Ok(parsed) => host_range(&parsed).map(|range| (parsed, range)).ok_or(invalid(subnet)),
+            Err(_) => Err(invalid(subnet)),
+        };
+        let parse_subnet6 = |subnet: String| match subnet.parse::<ipnet::Ipv6Net>().map(|subnet| subnet.trunc()){
+            Ok(parsed) if parsed.prefix_len() < 128 => Ok(parsed),
+            _ => Err(invalid(subnet)),
+        };
+        let gateway_out_of_range = |address: IpAddr| RuntimeError::AddressOutOfRange{
+            network: name.to_string(),
+            address,
+            interface: "gateway".to_string(),
+        };
+        let network = match config.network_type{
+            NetworkTypeConfig::Unmanaged { subnet, bridge_type, gateway, reserved, allocation_range, subnet6, gateway6, address_mode6 } => {
+                let ipv4 = match subnet{
+                    Some(subnet) => {
+                        let (subnet, hosts) = parse_subnet(subnet)?;
+                        let gateway = gateway.unwrap_or(hosts.start);
+                        if !hosts.contains(&gateway){
+                            return Err(gateway_out_of_range(gateway.into()));
+                        }
+                        let mut addresses = BTreeMap::new();
+                        addresses.insert(u32::from(gateway), gateway);
+                        Some(Ipv4Runtime{
+                            subnet,
+                            addresses,
+                            gateway,
+                            reserved,
+                            allocation_range: allocation_range.unwrap_or(hosts),
+                        })
+                    },
+                    None => None,
+                };
+                let ipv6 = match subnet6{
+                    Some(subnet) => {
+                        let subnet = parse_subnet6(subnet)?;
+                        let gateway = gateway6.unwrap_or(Ipv6Addr::from(u128::from(subnet.network()) + 1));
+                        if !subnet.contains(&gateway) || gateway == subnet.network(){
+                            return Err(gateway_out_of_range(gateway.into()));
+                        }
+                        let mut addresses = BTreeMap::new();
+                        addresses.insert(u128::from(gateway), gateway);
+                        Some(Ipv6Runtime{
+                            subnet,
+                            addresses,
+                            gateway,
+                            mode: address_mode6,
+                        })
+                    },
+                    None => None,
+                };
                 NetworkRuntime{
                     network_type: NetworkTypeRuntime::Unmanaged{
+                        ipv4,
+                        ipv6,
+                        bridge: BridgeRuntime::new(topology, name, bridge_type),
+                    }
+                }
+            },
+            NetworkTypeConfig::Libvirt { mode, subnet, dhcp_range, domain } => {
+                let (subnet, hosts) = parse_subnet(subnet)?;
+                let gateway = hosts.start;
+                let mut addresses = BTreeMap::new();
+                addresses.insert(u32::from(gateway), gateway);
+                NetworkRuntime{
+                    network_type: NetworkTypeRuntime::Libvirt{
+                        name: format!("{}-{}", topology, name),
+                        mode,
                         subnet,
                         addresses,
                         gateway,
+                        dhcp_range,
+                        domain,
                     }
                 }
             },
@@ -103,19 +417,67 @@ impl From<NetworkConfig> for NetworkRuntime{
                     }
                 }
             }
-        }
+        };
+        Ok(network)
+    }
+}
+
+// the state stores the used ipv6 addresses as a list, u128 keys don't survive the buffering
+// of the untagged NetworkTypeRuntime
+mod ipv6_addresses{
+    use std::collections::BTreeMap;
+    use std::net::Ipv6Addr;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(addresses: &BTreeMap<u128, Ipv6Addr>, serializer: S) -> Result<S::Ok, S::Error>{
+        serializer.collect_seq(addresses.values())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<u128, Ipv6Addr>, D::Error>{
+        let addresses = Vec::<Ipv6Addr>::deserialize(deserializer)?;
+        Ok(addresses.into_iter().map(|address| (u128::from(address), address)).collect())
+    }
+}
+
+// all addresses of a subnet usable by hosts, without network and broadcast address, None for
+// /31 and /32 subnets
+fn host_range(subnet: &ipnet::Ipv4Net) -> Option<AddressRange>{
+    let start = u32::from(subnet.network()).checked_add(1)?;
+    let end = u32::from(subnet.broadcast()).checked_sub(1)?;
+    (start <= end).then_some(AddressRange{
+        start: Ipv4Addr::from(start),
+        end: Ipv4Addr::from(end),
+    })
+}
 
+// modified eui-64: the mac with ff:fe in the middle and the universal/local bit flipped
+fn eui64_address(subnet: &ipnet::Ipv6Net, mac: MacAddr) -> Ipv6Addr{
+    let interface_id = u64::from_be_bytes([mac.0 ^ 0x02, mac.1, mac.2, 0xff, 0xfe, mac.3, mac.4, mac.5]);
+    Ipv6Addr::from(u128::from(subnet.network()) | u128::from(interface_id))
+}
 
+fn next_free_address(addresses: &mut BTreeMap<u32, Ipv4Addr>, range: AddressRange, reserved: &[AddressRange]) -> Option<Ipv4Addr>{
+    for candidate in u32::from(range.start)..=u32::from(range.end){
+        let address = Ipv4Addr::from(candidate);
+        if reserved.iter().any(|reserved| reserved.contains(&address)){
+            continue;
+        }
+        if let Entry::Vacant(entry) = addresses.entry(candidate){
+            entry.insert(address);
+            return Some(address);
+        }
     }
+    None
 }
 
-impl From<&Config> for HashMap<String,NetworkRuntime>{
-    fn from(config: &Config) -> Self {
-        let mut networks = HashMap::new();
+impl TryFrom<&Config> for BTreeMap<String,NetworkRuntime>{
+    type Error = RuntimeError;
+    fn try_from(config: &Config) -> Result<Self, Self::Error> {
+        let mut networks = BTreeMap::new();
         for (name, network) in &config.networks{
-            networks.insert(name.to_string(), NetworkRuntime::from(network.clone()));
+            networks.insert(name.to_string(), NetworkRuntime::new(config.topology_name(), name, network.clone())?);
         }
-        networks
+        Ok(networks)
     }
 }
 
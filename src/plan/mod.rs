@@ -0,0 +1 @@
+pub mod plan;
\ No newline at end of file
@@ -0,0 +1,417 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::net::IpAddr;
+
+use crate::domain::domain::{is_q35, HOTPLUG_PORTS};
+use crate::instance::instance::InstanceRuntime;
+use crate::interface::interface::InterfaceRuntime;
+use crate::network::network::{AddressRange, NetworkRuntime, NetworkTypeRuntime};
+use crate::runtime::runtime::Runtime;
+use crate::virt_manager::virt_manager::{DhcpHost, VirtManager};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action{
+    Create,
+    Update,
+    Replace,
+    Destroy,
+}
+
+impl Action{
+    fn symbol(&self) -> &'static str{
+        match self{
+            Action::Create => "+",
+            Action::Update => "~",
+            Action::Replace => "-/+",
+            Action::Destroy => "-",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkChange{
+    pub action: Action,
+    pub details: Vec<String>,
+    // dhcp host entries of a libvirt network, updated in place
+    pub hosts_added: Vec<DhcpHost>,
+    pub hosts_removed: Vec<DhcpHost>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstanceChange{
+    pub action: Action,
+    pub details: Vec<String>,
+    pub autostart: bool,
+    // hot-plugged and unplugged interfaces, a changed interface is unplugged and plugged again
+    pub interfaces_added: BTreeMap<String, InterfaceRuntime>,
+    pub interfaces_removed: BTreeMap<String, InterfaceRuntime>,
+    // the network-config changed, the seed iso has to be regenerated
+    pub guest_config: bool,
+    // addresses or routes of existing interfaces changed, the guest only applies them on boot
+    pub restart: bool,
+    // the domain definition changed, it is defined again and restarted, the disk is kept
+    pub redefine: bool,
+}
+
+// the changes needed to get from the deployed runtime to the desired one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan{
+    pub topology: String,
+    pub networks: BTreeMap<String, NetworkChange>,
+    pub instances: BTreeMap<String, InstanceChange>,
+}
+
+impl InstanceChange{
+    fn new(action: Action, details: Vec<String>) -> InstanceChange{
+        InstanceChange{
+            action,
+            details,
+            autostart: false,
+            interfaces_added: BTreeMap::new(),
+            interfaces_removed: BTreeMap::new(),
+            guest_config: false,
+            restart: false,
+            redefine: false,
+        }
+    }
+}
+
+impl Plan{
+    pub fn new(runtime: &Runtime, deployed: Option<&Runtime>) -> Plan{
+        let empty = Runtime{
+            name: runtime.name.clone(),
+            user_config: None,
+            storage: runtime.storage.clone(),
+            instances: BTreeMap::new(),
+            networks: BTreeMap::new(),
+        };
+        let deployed = deployed.unwrap_or(&empty);
+        let mut networks = BTreeMap::new();
+        for (name, network) in &runtime.networks{
+            let change = match deployed.networks.get(name){
+                Some(old) => diff_network(name, old, network, deployed, runtime),
+                None => Some(NetworkChange{
+                    action: Action::Create,
+                    details: describe_network(network),
+                    hosts_added: Vec::new(),
+                    hosts_removed: Vec::new(),
+                }),
+            };
+            if let Some(change) = change{
+                networks.insert(name.clone(), change);
+            }
+        }
+        for name in deployed.networks.keys(){
+            if !runtime.networks.contains_key(name){
+                networks.insert(name.clone(), NetworkChange{
+                    action: Action::Destroy,
+                    details: Vec::new(),
+                    hosts_added: Vec::new(),
+                    hosts_removed: Vec::new(),
+                });
+            }
+        }
+        let replaced: BTreeSet<&String> = networks.iter()
+            .filter(|(_, change)| change.action == Action::Replace)
+            .map(|(name, _)| name)
+            .collect();
+        let mut instances = BTreeMap::new();
+        for (name, instance) in &runtime.instances{
+            let change = match deployed.instances.get(name){
+                // disks and seed isos are volumes of the pool, they are recreated in the new one
+                Some(old) if deployed.storage.pool != runtime.storage.pool => {
+                    let mut details = vec![format!("~ storage pool {} -> {}", deployed.storage.pool, runtime.storage.pool)];
+                    details.extend(diff_instance(old, instance, &replaced).map(|change| change.details).unwrap_or_default());
+                    Some(InstanceChange::new(Action::Replace, details))
+                },
+                Some(old) => diff_instance(old, instance, &replaced),
+                None => Some(InstanceChange::new(Action::Create, describe_instance(instance))),
+            };
+            if let Some(change) = change{
+                instances.insert(name.clone(), change);
+            }
+        }
+        for name in deployed.instances.keys(){
+            if !runtime.instances.contains_key(name){
+                instances.insert(name.clone(), InstanceChange::new(Action::Destroy, Vec::new()));
+            }
+        }
+        Plan{
+            topology: runtime.name.clone(),
+            networks,
+            instances,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.networks.is_empty() && self.instances.is_empty()
+    }
+
+    fn count(&self, action: Action) -> usize{
+        self.networks.values().filter(|change| change.action == action).count() +
+            self.instances.values().filter(|change| change.action == action).count()
+    }
+}
+
+impl fmt::Display for Plan{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty(){
+            return write!(f, "No changes, topology {} is up to date.", self.topology);
+        }
+        for (name, change) in &self.networks{
+            writeln!(f, "{} network {}", change.action.symbol(), name)?;
+            for detail in &change.details{
+                writeln!(f, "    {}", detail)?;
+            }
+        }
+        for (name, change) in &self.instances{
+            writeln!(f, "{} instance {}", change.action.symbol(), name)?;
+            for detail in &change.details{
+                writeln!(f, "    {}", detail)?;
+            }
+        }
+        write!(f, "\nPlan: {} to create, {} to update, {} to replace, {} to destroy.",
+            self.count(Action::Create), self.count(Action::Update), self.count(Action::Replace), self.count(Action::Destroy))
+    }
+}
+
+fn diff_network(name: &str, old: &NetworkRuntime, new: &NetworkRuntime, deployed: &Runtime, runtime: &Runtime) -> Option<NetworkChange>{
+    let mut details = Vec::new();
+    let mut replace = false;
+    let mut hosts_added = Vec::new();
+    let mut hosts_removed = Vec::new();
+    match (&old.network_type, &new.network_type){
+        (NetworkTypeRuntime::Managed{name: old}, NetworkTypeRuntime::Managed{name: new}) => {
+            // not owned by virt-rs, the attached interfaces change instead
+            changed(&mut details, "libvirt network", old, new);
+        },
+        (NetworkTypeRuntime::Unmanaged{ipv4: old_ipv4, ipv6: old_ipv6, bridge: old_bridge},
+         NetworkTypeRuntime::Unmanaged{ipv4: new_ipv4, ipv6: new_ipv6, bridge: new_bridge}) => {
+            replace |= changed(&mut details, "bridge", &old_bridge.name, &new_bridge.name);
+            replace |= changed(&mut details, "bridge type", &format!("{:?}", old_bridge.bridge_type), &format!("{:?}", new_bridge.bridge_type));
+            // addresses live in the guests only, the bridge stays as it is
+            changed(&mut details, "subnet", &display(old_ipv4.as_ref().map(|ipv4| ipv4.subnet)), &display(new_ipv4.as_ref().map(|ipv4| ipv4.subnet)));
+            changed(&mut details, "gateway", &display(old_ipv4.as_ref().map(|ipv4| ipv4.gateway)), &display(new_ipv4.as_ref().map(|ipv4| ipv4.gateway)));
+            changed(&mut details, "subnet6", &display(old_ipv6.as_ref().map(|ipv6| ipv6.subnet)), &display(new_ipv6.as_ref().map(|ipv6| ipv6.subnet)));
+            changed(&mut details, "gateway6", &display(old_ipv6.as_ref().map(|ipv6| ipv6.gateway)), &display(new_ipv6.as_ref().map(|ipv6| ipv6.gateway)));
+        },
+        (NetworkTypeRuntime::Libvirt{name: old_name, mode: old_mode, subnet: old_subnet, gateway: old_gateway, dhcp_range: old_range, domain: old_domain, ..},
+         NetworkTypeRuntime::Libvirt{name: new_name, mode: new_mode, subnet: new_subnet, gateway: new_gateway, dhcp_range: new_range, domain: new_domain, ..}) => {
+            replace |= changed(&mut details, "libvirt network", old_name, new_name);
+            replace |= changed(&mut details, "mode", &format!("{:?}", old_mode), &format!("{:?}", new_mode));
+            replace |= changed(&mut details, "subnet", old_subnet, new_subnet);
+            replace |= changed(&mut details, "gateway", old_gateway, new_gateway);
+            replace |= changed(&mut details, "dhcp range", &display_range(old_range), &display_range(new_range));
+            replace |= changed(&mut details, "domain", &display(old_domain.as_ref()), &display(new_domain.as_ref()));
+            let old_hosts = VirtManager::dhcp_hosts(name, deployed);
+            let new_hosts = VirtManager::dhcp_hosts(name, runtime);
+            hosts_removed = old_hosts.iter().filter(|host| !new_hosts.contains(host)).cloned().collect();
+            hosts_added = new_hosts.iter().filter(|host| !old_hosts.contains(host)).cloned().collect();
+            for host in &hosts_removed{
+                details.push(format!("- dhcp host {} {} {}", host.name, host.mac, host.ip));
+            }
+            for host in &hosts_added{
+                details.push(format!("+ dhcp host {} {} {}", host.name, host.mac, host.ip));
+            }
+            // hosts can only be updated in place if the network already has a dhcp section and keeps it
+            let dhcp = |range: &Option<AddressRange>, hosts: &Vec<DhcpHost>| range.is_some() || !hosts.is_empty();
+            if (!hosts_added.is_empty() || !hosts_removed.is_empty()) && dhcp(old_range, &old_hosts) != dhcp(new_range, &new_hosts){
+                replace = true;
+            }
+        },
+        _ => {
+            details.push(format!("~ type {} -> {}", type_name(old), type_name(new)));
+            replace = true;
+        },
+    }
+    if details.is_empty(){
+        return None;
+    }
+    Some(NetworkChange{
+        action: if replace { Action::Replace } else { Action::Update },
+        details,
+        hosts_added,
+        hosts_removed,
+    })
+}
+
+fn diff_instance(old: &InstanceRuntime, new: &InstanceRuntime, replaced: &BTreeSet<&String>) -> Option<InstanceChange>{
+    let mut change = InstanceChange::new(Action::Update, Vec::new());
+    let details = &mut change.details;
+    let mut replace = false;
+    change.redefine |= changed(details, "vcpu", &old.vcpu, &new.vcpu);
+    change.redefine |= changed(details, "memory", &old.memory, &new.memory);
+    // only a new image or disk needs a new disk volume
+    replace |= changed(details, "image", &old.image, &new.image);
+    replace |= changed(details, "disk", &old.disk, &new.disk);
+    change.redefine |= changed(details, "domain", &old.domain, &new.domain);
+    // state written before templates were hashed has no hash to compare
+    if old.template_hash.is_some(){
+        change.redefine |= changed(details, "template content", &display(old.template_hash.as_ref()), &display(new.template_hash.as_ref()));
+    }
+    change.redefine |= changed(details, "cpu", &old.cpu, &new.cpu);
+    change.redefine |= changed(details, "hugepages", &display(old.hugepages), &display(new.hugepages));
+    replace |= changed(details, "persistent", &old.persistent, &new.persistent);
+    change.autostart = changed(details, "autostart", &old.autostart, &new.autostart);
+    for (name, interface) in &new.interfaces{
+        let old_interface = match old.interfaces.get(name){
+            Some(old_interface) => old_interface,
+            None => {
+                details.push(format!("+ interface {} ({})", name, describe_interface(interface)));
+                change.interfaces_added.insert(name.clone(), interface.clone());
+                continue;
+            },
+        };
+        let mut interface_details = Vec::new();
+        let mut replug = false;
+        replug |= changed(&mut interface_details, "network", &old_interface.network, &interface.network);
+        replug |= changed(&mut interface_details, "mac", &old_interface.mac, &interface.mac);
+        replug |= changed(&mut interface_details, "mtu", &old_interface.mtu, &interface.mtu);
+        replug |= changed(&mut interface_details, "bridge", &display(old_interface.bridge.as_ref().map(|bridge| &bridge.name)), &display(interface.bridge.as_ref().map(|bridge| &bridge.name)));
+        replug |= changed(&mut interface_details, "libvirt network", &display(old_interface.managed.as_ref()), &display(interface.managed.as_ref()));
+        change.restart |= changed(&mut interface_details, "address", &display(old_interface.address), &display(interface.address));
+        change.restart |= changed(&mut interface_details, "address6", &display(old_interface.address6), &display(interface.address6));
+        for detail in interface_details{
+            details.push(format!("~ interface {}: {}", name, detail.trim_start_matches("~ ")));
+        }
+        if replaced.contains(&interface.network){
+            details.push(format!("~ interface {}: network {} is replaced", name, interface.network));
+            replug = true;
+        }
+        if replug{
+            change.interfaces_removed.insert(name.clone(), old_interface.clone());
+            change.interfaces_added.insert(name.clone(), interface.clone());
+        }
+    }
+    for (name, interface) in &old.interfaces{
+        if !new.interfaces.contains_key(name){
+            details.push(format!("- interface {} ({})", name, describe_interface(interface)));
+            change.interfaces_removed.insert(name.clone(), interface.clone());
+        }
+    }
+    // more interfaces than spare root ports can't be hot-plugged, the new definition has ports for all of them
+    let plugged = change.interfaces_added.len().saturating_sub(change.interfaces_removed.len());
+    if plugged > HOTPLUG_PORTS && is_q35(&new.domain.machine){
+        details.push(format!("~ {} interfaces added, more than the {} hot-plug ports", plugged, HOTPLUG_PORTS));
+        change.redefine = true;
+    }
+    let old_routes = routes(old);
+    let new_routes = routes(new);
+    for (destination, next_hops) in &new_routes{
+        match old_routes.get(destination){
+            Some(old_next_hops) if old_next_hops != next_hops => {
+                details.push(format!("~ route {} via {} -> via {}", destination, join(old_next_hops), join(next_hops)));
+                change.restart = true;
+            },
+            Some(_) => {},
+            None => {
+                details.push(format!("+ route {} via {}", destination, join(next_hops)));
+                change.restart = true;
+            },
+        }
+    }
+    for (destination, next_hops) in &old_routes{
+        if !new_routes.contains_key(destination){
+            details.push(format!("- route {} via {}", destination, join(next_hops)));
+            change.restart = true;
+        }
+    }
+    change.guest_config = change.restart || !change.interfaces_added.is_empty() || !change.interfaces_removed.is_empty();
+    // a transient domain can't be restarted or redefined, it is gone once it stops
+    if (change.restart || change.redefine) && !new.persistent{
+        replace = true;
+    }
+    if change.details.is_empty(){
+        return None;
+    }
+    if replace{
+        change.action = Action::Replace;
+    }
+    Some(change)
+}
+
+fn describe_network(network: &NetworkRuntime) -> Vec<String>{
+    let mut details = Vec::new();
+    match &network.network_type{
+        NetworkTypeRuntime::Managed{name} => details.push(format!("libvirt network {}", name)),
+        NetworkTypeRuntime::Libvirt{name, mode, subnet, gateway, ..} => {
+            details.push(format!("libvirt network {}, mode {:?}", name, mode));
+            details.push(format!("subnet {}, gateway {}", subnet, gateway));
+        },
+        NetworkTypeRuntime::Unmanaged{ipv4, ipv6, bridge} => {
+            details.push(format!("bridge {} ({:?})", bridge.name, bridge.bridge_type));
+            if let Some(ipv4) = ipv4{
+                details.push(format!("subnet {}, gateway {}", ipv4.subnet, ipv4.gateway));
+            }
+            if let Some(ipv6) = ipv6{
+                details.push(format!("subnet {}, gateway {}", ipv6.subnet, ipv6.gateway));
+            }
+        },
+    }
+    details
+}
+
+fn describe_instance(instance: &InstanceRuntime) -> Vec<String>{
+    let mut details = vec![format!("vcpu {}, memory {}, image {}, disk {}, {}", instance.vcpu, instance.memory, instance.image, instance.disk, instance.domain)];
+    if !instance.cpu.is_default(){
+        details.push(format!("cpu {}", instance.cpu));
+    }
+    if let Some(hugepages) = instance.hugepages{
+        details.push(format!("hugepages of {}", hugepages));
+    }
+    for (name, interface) in &instance.interfaces{
+        details.push(format!("interface {} ({})", name, describe_interface(interface)));
+    }
+    for (destination, next_hops) in routes(instance){
+        details.push(format!("route {} via {}", destination, join(&next_hops)));
+    }
+    details
+}
+
+fn describe_interface(interface: &InterfaceRuntime) -> String{
+    let mut description = format!("network {}", interface.network);
+    for address in interface.address.map(IpAddr::V4).into_iter().chain(interface.address6.map(IpAddr::V6)){
+        description.push_str(&format!(", address {}", address));
+    }
+    format!("{}, mtu {}", description, interface.mtu)
+}
+
+// the routes of all route tables of an instance
+fn routes(instance: &InstanceRuntime) -> BTreeMap<ipnet::IpNet, Vec<IpAddr>>{
+    let mut routes: BTreeMap<ipnet::IpNet, Vec<IpAddr>> = BTreeMap::new();
+    for route_table in instance.route_tables.values(){
+        for (destination, next_hops) in &route_table.routes{
+            routes.entry(*destination).or_default().extend(next_hops);
+        }
+    }
+    routes
+}
+
+fn type_name(network: &NetworkRuntime) -> &'static str{
+    match network.network_type{
+        NetworkTypeRuntime::Managed{..} => "managed",
+        NetworkTypeRuntime::Libvirt{..} => "libvirt",
+        NetworkTypeRuntime::Unmanaged{..} => "unmanaged",
+    }
+}
+
+// records a "~ field old -> new" detail, returns true if the value changed
+fn changed<T: PartialEq + fmt::Display + ?Sized>(details: &mut Vec<String>, field: &str, old: &T, new: &T) -> bool{
+    if old == new{
+        return false;
+    }
+    details.push(format!("~ {} {} -> {}", field, old, new));
+    true
+}
+
+fn display<T: fmt::Display>(value: Option<T>) -> String{
+    value.map(|value| value.to_string()).unwrap_or("none".to_string())
+}
+
+fn display_range(range: &Option<AddressRange>) -> String{
+    display(range.as_ref().map(|range| format!("{}-{}", range.start, range.end)))
+}
+
+fn join(addresses: &[IpAddr]) -> String{
+    addresses.iter().map(|address| address.to_string()).collect::<Vec<String>>().join(", ")
+}
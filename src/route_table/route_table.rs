@@ -1,15 +1,16 @@
-use std::collections::HashMap;
-use std::net::Ipv4Addr;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
 
 use serde::{Deserialize, Serialize};
 use crate::instance::instance::InstanceRuntime;
-use crate::network::network::{NetworkRuntime, NetworkTypeRuntime};
+use crate::network::network::NetworkRuntime;
 use crate::object::object::Object;
 use crate::config::config::Config;
+use crate::runtime::runtime::RuntimeError;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RouteTableConfig{
-    pub routes: HashMap<String, Vec<InstanceInterface>>,
+    pub routes: BTreeMap<String, Vec<InstanceInterface>>,
     pub instance: String,
 }
 
@@ -20,7 +21,7 @@ pub struct InstanceInterface{
 }
 
 impl RouteTableConfig{
-    pub fn new(instance: &str, routes: HashMap<String, Vec<InstanceInterface>>) -> RouteTableConfig{
+    pub fn new(instance: &str, routes: BTreeMap<String, Vec<InstanceInterface>>) -> RouteTableConfig{
         RouteTableConfig{
             instance: instance.to_string(),
             routes,
@@ -42,40 +43,76 @@ impl <'a>Object<'a, RouteTableConfig> for Config {
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RouteTableRuntime{
-    pub routes: HashMap<ipnet::Ipv4Net, Vec<Ipv4Addr>>,
+    pub routes: BTreeMap<ipnet::IpNet, Vec<IpAddr>>,
 }
 
 impl RouteTableRuntime{
-    pub fn configure(config: &Config, networks: &HashMap<String, NetworkRuntime>, instances: &mut HashMap<String, InstanceRuntime>){
+    pub fn configure(config: &Config, networks: &BTreeMap<String, NetworkRuntime>, instances: &mut BTreeMap<String, InstanceRuntime>) -> Result<(), RuntimeError>{
         for (name, route_table) in &config.route_tables{
             let instances_clone = instances.clone();
-            if let Some(instance) = instances.get_mut(&route_table.instance){
-                let mut routes = HashMap::new();
-                for (destination, next_hops) in &route_table.routes{
-                    if let Some(network) = networks.get(destination){
-                        match network.network_type{
-                            NetworkTypeRuntime::Unmanaged{subnet, ref addresses, gateway} => {
-                                for next_hop in next_hops{
-                                    if let Some(next_hop_instance) = instances_clone.get(&next_hop.instance){
-                                        if let Some(next_hop_interface) = next_hop_instance.interfaces.get(&next_hop.interface){
-                                            let destination = subnet;
-                                            let next_hop = next_hop_interface.address;
-                                            routes.entry(destination).or_insert(Vec::new()).push(next_hop.unwrap());
-                                        }
-                                    }
-                                }
-                            },
-                            _ => {},
-                        }
-                    }
+            let instance = instances.get_mut(&route_table.instance).ok_or_else(|| RuntimeError::UnknownReference{
+                kind: "instance",
+                name: route_table.instance.clone(),
+                referenced_by: format!("route table {}", name),
+            })?;
+            let mut routes = BTreeMap::new();
+            for (destination, next_hops) in &route_table.routes{
+                let network = networks.get(destination).ok_or_else(|| RuntimeError::UnknownReference{
+                    kind: "network",
+                    name: destination.clone(),
+                    referenced_by: format!("route table {}", name),
+                })?;
+                // routes to networks without a subnet (libvirt managed ones) can't be expressed
+                let destination4 = network.subnet().map(ipnet::IpNet::from);
+                let destination6 = network.subnet6().map(ipnet::IpNet::from);
+                if destination4.is_none() && destination6.is_none(){
+                    continue;
                 }
-                if routes.len() > 0{
-                    let route_table = RouteTableRuntime{
-                        routes,
+                for next_hop in next_hops{
+                    let unreachable = |reason: String| RuntimeError::NextHopUnreachable{
+                        route_table: name.clone(),
+                        interface: next_hop.interface.clone(),
+                        reason,
                     };
-                    instance.route_tables.insert(name.clone(), route_table);
+                    let next_hop_instance = instances_clone.get(&next_hop.instance).ok_or_else(|| RuntimeError::UnknownReference{
+                        kind: "instance",
+                        name: next_hop.instance.clone(),
+                        referenced_by: format!("route table {}", name),
+                    })?;
+                    let next_hop_interface = next_hop_instance.interfaces.get(&next_hop.interface)
+                        .ok_or_else(|| unreachable(format!("instance {} has no interface {}", next_hop.instance, next_hop.interface)))?;
+                    if next_hop_interface.address.is_none() && next_hop_interface.address6.is_none(){
+                        return Err(unreachable(format!("network {} doesn't assign addresses", next_hop_interface.network)));
+                    }
+                    // the validator warns about next hops off the links of the instance, they can't be used
+                    let connected = instance.interfaces.values().any(|interface| interface.network == next_hop_interface.network);
+                    if !connected{
+                        continue;
+                    }
+                    // each address family is routed through the next hop address of the same family
+                    let families = [
+                        (destination4, next_hop_interface.address.map(IpAddr::V4)),
+                        (destination6, next_hop_interface.address6.map(IpAddr::V6)),
+                    ];
+                    let mut routed = false;
+                    for (destination, address) in families{
+                        if let (Some(destination), Some(address)) = (destination, address){
+                            routes.entry(destination).or_insert(Vec::new()).push(address);
+                            routed = true;
+                        }
+                    }
+                    if !routed{
+                        return Err(unreachable(format!("network {} shares no address family with destination {}", next_hop_interface.network, destination)));
+                    }
                 }
             }
+            if !routes.is_empty(){
+                let route_table = RouteTableRuntime{
+                    routes,
+                };
+                instance.route_tables.insert(name.clone(), route_table);
+            }
         }
+        Ok(())
     }
 }
\ No newline at end of file
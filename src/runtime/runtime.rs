@@ -1,26 +1,145 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::net::IpAddr;
 
+use pnet::util::MacAddr;
 use serde::{Deserialize, Serialize};
 
-use crate::{instance::instance::InstanceRuntime, network::network::NetworkRuntime, config::config::{Config, UserConfig}, interface::interface::InterfaceRuntime, route_table::route_table::RouteTableRuntime};
+use crate::{instance::instance::InstanceRuntime, network::network::NetworkRuntime, config::config::{Config, StorageConfig, UserConfig}, interface::interface::InterfaceRuntime, route_table::route_table::RouteTableRuntime};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Runtime{
+    pub name: String,
     pub user_config: Option<UserConfig>,
-    pub instances: HashMap<String, InstanceRuntime>,
-    pub networks: HashMap<String, NetworkRuntime>,
+    // state written before the pool was configurable used the default pool
+    #[serde(default)]
+    pub storage: StorageConfig,
+    pub instances: BTreeMap<String, InstanceRuntime>,
+    pub networks: BTreeMap<String, NetworkRuntime>,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError{
+    UnknownReference{
+        kind: &'static str,
+        name: String,
+        referenced_by: String,
+    },
+    SubnetExhausted{
+        network: String,
+        subnet: ipnet::IpNet,
+    },
+    InvalidCidr{
+        network: String,
+        subnet: String,
+    },
+    AddressConflict{
+        network: String,
+        address: IpAddr,
+        interface: String,
+    },
+    AddressOutOfRange{
+        network: String,
+        address: IpAddr,
+        interface: String,
+    },
+    MacConflict{
+        mac: MacAddr,
+        interface: String,
+        other: String,
+    },
+    NextHopUnreachable{
+        route_table: String,
+        interface: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for RuntimeError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            RuntimeError::UnknownReference{kind, name, referenced_by} => write!(f, "unknown {} '{}' referenced by {}", kind, name, referenced_by),
+            RuntimeError::SubnetExhausted{network, subnet} => write!(f, "no free address left in subnet {} of network {}", subnet, network),
+            RuntimeError::InvalidCidr{network, subnet} => write!(f, "invalid subnet '{}' in network {}", subnet, network),
+            RuntimeError::AddressConflict{network, address, interface} => write!(f, "address {} of interface {} is already in use on network {}", address, interface, network),
+            RuntimeError::AddressOutOfRange{network, address, interface} => write!(f, "address {} of interface {} is not a usable address of network {}", address, interface, network),
+            RuntimeError::MacConflict{mac, interface, other} => write!(f, "mac {} of interface {} is already used by interface {}, set a mac for one of them", mac, interface, other),
+            RuntimeError::NextHopUnreachable{route_table, interface, reason} => write!(f, "next hop {} of route table {} is unreachable: {}", interface, route_table, reason),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError{}
+
 impl Runtime{
-    pub fn build(config: &Config) -> Runtime{
-        let mut networks: HashMap<String, NetworkRuntime> = HashMap::from(config);
-        let mut instances: HashMap<String, InstanceRuntime> = HashMap::from(config);
-        InterfaceRuntime::configure(config, &mut networks, &mut instances);
-        RouteTableRuntime::configure(config, &networks, &mut instances);
-        Runtime{
+    pub fn build(config: &Config) -> Result<Runtime, RuntimeError>{
+        let mut networks = BTreeMap::<String, NetworkRuntime>::try_from(config)?;
+        let mut instances: BTreeMap<String, InstanceRuntime> = BTreeMap::from(config);
+        InterfaceRuntime::configure(config, &mut networks, &mut instances)?;
+        RouteTableRuntime::configure(config, &networks, &mut instances)?;
+        Ok(Runtime{
+            name: config.topology_name().to_string(),
             user_config: config.user_config.clone(),
+            storage: config.storage.clone(),
             instances,
             networks,
+        })
+    }
+
+    // what is deployed after applying this runtime, when the given instances failed: they keep
+    // their previous runtime or are left out if they weren't deployed, so the next plan retries them
+    pub fn applied(mut self, previous: Option<&Runtime>, failed: &[&String]) -> Runtime{
+        for name in failed{
+            match previous.and_then(|previous| previous.instances.get(*name)){
+                Some(instance) => { self.instances.insert((*name).clone(), instance.clone()); },
+                None => { self.instances.remove(*name); },
+            }
+        }
+        self
+    }
+
+    // builds the runtime like build, but interfaces keep the addresses they got in the previous
+    // runtime, as long as those could still be allocated and aren't pinned to another interface by the config
+    pub fn rebuild(config: &Config, previous: &Runtime) -> Result<Runtime, RuntimeError>{
+        let mut config = config.clone();
+        let networks = BTreeMap::<String, NetworkRuntime>::try_from(&config)?;
+        let mut taken = BTreeSet::new();
+        for interface in config.interfaces.values(){
+            let pinned = interface.address.map(IpAddr::V4).into_iter().chain(interface.address6.map(IpAddr::V6));
+            for address in pinned{
+                taken.insert((interface.network.clone(), address));
+            }
+        }
+        for (name, interface) in config.interfaces.iter_mut(){
+            let previous_interface = previous.instances.get(&interface.instance)
+                .and_then(|instance| instance.interfaces.get(name))
+                .filter(|previous_interface| previous_interface.network == interface.network);
+            let (previous_interface, network) = match (previous_interface, networks.get(&interface.network)){
+                (Some(previous_interface), Some(network)) => (previous_interface, network),
+                _ => continue,
+            };
+            let network_name = interface.network.clone();
+            let mut keep = |address: IpAddr| {
+                let gateway = match address{
+                    IpAddr::V4(_) => network.gateway().map(IpAddr::V4),
+                    IpAddr::V6(_) => network.gateway6().map(IpAddr::V6),
+                };
+                network.is_allocatable(address) && gateway != Some(address) && taken.insert((network_name.clone(), address))
+            };
+            if interface.address.is_none(){
+                interface.address = previous_interface.address.filter(|address| keep(IpAddr::V4(*address)));
+            }
+            if interface.address6.is_none(){
+                interface.address6 = previous_interface.address6.filter(|address| keep(IpAddr::V6(*address)));
+            }
+        }
+        let mut runtime = Runtime::build(&config)?;
+        // deployed domains keep their uuid, also one derived differently by an older version
+        for (name, instance) in runtime.instances.iter_mut(){
+            if let Some(previous_instance) = previous.instances.get(name){
+                instance.uuid = previous_instance.uuid.clone();
+            }
         }
+        Ok(runtime)
     }
 }
\ No newline at end of file
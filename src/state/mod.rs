@@ -0,0 +1 @@
+pub mod state;
\ No newline at end of file
@@ -0,0 +1,113 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use crate::network::network::{BridgeRuntime, NetworkTypeRuntime};
+use crate::runtime::runtime::Runtime;
+use crate::virt_manager::virt_manager::VirtManager;
+
+pub const DEFAULT_STATE_DIR: &str = ".virt-rs";
+
+// what a topology was deployed as and what was actually created on the host for it
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct State{
+    pub runtime: Runtime,
+    pub resources: Resources,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Resources{
+    #[serde(default)]
+    pub instances: BTreeMap<String, InstanceResources>,
+    // keyed by the topology network name
+    #[serde(default)]
+    pub bridges: BTreeMap<String, BridgeRuntime>,
+    #[serde(default)]
+    pub libvirt_networks: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct InstanceResources{
+    pub domain: String,
+    // state written before uuids were recorded has none, the domain is then taken as it is
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    // state written before disks were volumes has only files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool: Option<String>,
+    #[serde(default)]
+    pub volumes: Vec<String>,
+    pub files: Vec<String>,
+    pub taps: Vec<String>,
+}
+
+impl State{
+    pub fn new(runtime: Runtime, resources: Resources) -> State{
+        State{
+            runtime,
+            resources,
+        }
+    }
+
+    // a relative state dir is next to the config, so the state is found from any working directory
+    pub fn path(config: &str, state_dir: &str, topology: &str) -> PathBuf{
+        let base = Path::new(config).parent().unwrap_or(Path::new(""));
+        base.join(state_dir).join(format!("{}.yaml", topology))
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Option<State>>{
+        if !path.exists(){
+            return Ok(None);
+        }
+        let state = std::fs::read_to_string(path).with_context(|| format!("failed to read state {}", path.display()))?;
+        let state = serde_yaml::from_str(&state).with_context(|| format!("failed to parse state {}", path.display()))?;
+        Ok(Some(state))
+    }
+
+    // written to a temporary file first, so an interrupted run never leaves a truncated state behind
+    pub fn save(&self, path: &Path) -> anyhow::Result<()>{
+        if let Some(dir) = path.parent(){
+            std::fs::create_dir_all(dir).with_context(|| format!("failed to create state dir {}", dir.display()))?;
+        }
+        let tmp = path.with_extension("yaml.tmp");
+        std::fs::write(&tmp, serde_yaml::to_string(self)?).with_context(|| format!("failed to write state {}", tmp.display()))?;
+        std::fs::rename(&tmp, path).with_context(|| format!("failed to write state {}", path.display()))?;
+        Ok(())
+    }
+
+    pub fn remove(path: &Path) -> anyhow::Result<()>{
+        if path.exists(){
+            std::fs::remove_file(path).with_context(|| format!("failed to remove state {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+impl Resources{
+    pub fn is_empty(&self) -> bool{
+        self.instances.is_empty() && self.bridges.is_empty() && self.libvirt_networks.is_empty()
+    }
+}
+
+// everything a runtime would create, used for topologies deployed before the state file existed
+impl From<&Runtime> for Resources{
+    fn from(runtime: &Runtime) -> Self {
+        let mut resources = Resources::default();
+        for (name, instance) in &runtime.instances{
+            resources.instances.insert(name.clone(), VirtManager::instance_resources(name, instance, &runtime.storage));
+        }
+        for (name, network) in &runtime.networks{
+            match &network.network_type{
+                NetworkTypeRuntime::Unmanaged{bridge, ..} => {
+                    resources.bridges.insert(name.clone(), bridge.clone());
+                },
+                NetworkTypeRuntime::Libvirt{name: libvirt_name, ..} => {
+                    resources.libvirt_networks.insert(name.clone(), libvirt_name.clone());
+                },
+                NetworkTypeRuntime::Managed{..} => {},
+            }
+        }
+        resources
+    }
+}
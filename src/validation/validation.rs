@@ -0,0 +1,522 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use ipnet::{Ipv4Net, Ipv6Net};
+use crate::config::config::Config;
+use crate::instance::instance::{CpuMode, InstanceConfig, Memory};
+use crate::network::network::{AddressRange, Ipv6AddressMode, NetworkTypeConfig};
+use crate::xml::xml::XmlPatch;
+
+// every nic needs its own pcie-root-port, domains get one per device and a few spare for
+// hot-plugging, this keeps them well below the 32 slots of the pcie root
+pub const MAX_INTERFACES_PER_INSTANCE: usize = 10;
+pub const MAX_VCPU: u16 = 256;
+pub const MIN_MEMORY_MIB: u64 = 128;
+// more is possible but rarely meant, a wrong unit is more likely
+pub const MAX_MEMORY_GIB: u64 = 256;
+// the smallest page size, hugepages are larger powers of two
+pub const MIN_PAGE_KIB: u64 = 4;
+pub const MIN_MTU: u32 = 68;
+pub const MAX_MTU: u32 = 65535;
+// linux limits interface names to 15 characters, interface names are used as tap names
+pub const MAX_LINK_NAME: usize = 15;
+// instance names are hostnames
+pub const MAX_HOSTNAME: usize = 63;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError{
+    pub path: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationErrors(pub Vec<ValidationError>);
+
+impl fmt::Display for ValidationError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+impl fmt::Display for ValidationErrors{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "config has {} problem(s):", self.0.len())?;
+        for error in &self.0{
+            write!(f, "\n  {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationErrors{}
+
+// subnets of all networks that assign addresses, per address family
+#[derive(Default)]
+struct Subnets{
+    ipv4: BTreeMap<String, Ipv4Net>,
+    ipv6: BTreeMap<String, Ipv6Net>,
+}
+
+impl Subnets{
+    fn contains(&self, network: &str) -> bool{
+        self.ipv4.contains_key(network) || self.ipv6.contains_key(network)
+    }
+}
+
+#[derive(Default)]
+struct Validator{
+    errors: Vec<ValidationError>,
+    warnings: Vec<ValidationError>,
+}
+
+impl Validator{
+    fn error(&mut self, path: String, message: String){
+        self.errors.push(ValidationError{path, message});
+    }
+    fn warning(&mut self, path: String, message: String){
+        self.warnings.push(ValidationError{path, message});
+    }
+}
+
+impl Config{
+    // returns the warnings, problems that don't prevent deploying the topology
+    pub fn validate(&self) -> Result<Vec<ValidationError>, ValidationErrors>{
+        let mut v = Validator::default();
+        if let Some(name) = &self.name{
+            validate_name(&mut v, "name".to_string(), name);
+        }
+        if self.storage.pool.is_empty(){
+            v.error("storage.pool".to_string(), "must not be empty".to_string());
+        }
+        if let Some(path) = &self.storage.path{
+            if !path.starts_with('/'){
+                v.error("storage.path".to_string(), format!("'{}' must be an absolute path", path));
+            }
+        }
+        validate_template(&mut v, "domain", &self.domain.template, &self.domain.patches);
+        let subnets = self.validate_networks(&mut v);
+        self.validate_instances(&mut v);
+        self.validate_interfaces(&mut v, &subnets);
+        self.validate_route_tables(&mut v, &subnets);
+        if v.errors.is_empty(){
+            Ok(v.warnings)
+        } else {
+            Err(ValidationErrors(v.errors))
+        }
+    }
+
+    fn validate_networks(&self, v: &mut Validator) -> Subnets{
+        let mut subnets = Subnets::default();
+        for (name, network) in &self.networks{
+            // part of the bridge name, long names are hashed
+            validate_name(v, format!("networks.{}", name), name);
+            let path = format!("networks.{}.network_type", name);
+            match &network.network_type{
+                NetworkTypeConfig::Managed{name: managed} => {
+                    if managed.is_empty(){
+                        v.error(format!("{}.name", path), "libvirt network name must not be empty".to_string());
+                    }
+                },
+                NetworkTypeConfig::Libvirt{subnet, dhcp_range, ..} => {
+                    let subnet = match parse_subnet(v, &format!("{}.subnet", path), subnet){
+                        Some(subnet) => subnet,
+                        None => continue,
+                    };
+                    if let Some(dhcp_range) = dhcp_range{
+                        validate_range(v, &format!("{}.dhcp_range", path), &subnet, dhcp_range);
+                    }
+                    subnets.ipv4.insert(name.clone(), subnet);
+                },
+                NetworkTypeConfig::Unmanaged{subnet, gateway, reserved, allocation_range, subnet6, gateway6, address_mode6, ..} => {
+                    if subnet.is_none() && subnet6.is_none(){
+                        v.error(path.clone(), "needs a subnet, a subnet6 or both".to_string());
+                    }
+                    let parsed = subnet.as_ref().and_then(|subnet| parse_subnet(v, &format!("{}.subnet", path), subnet));
+                    match parsed{
+                        Some(subnet) => {
+                            if let Some(gateway) = gateway{
+                                if !usable(&subnet, gateway){
+                                    v.error(format!("{}.gateway", path), format!("{} is not a usable address of {}", gateway, subnet));
+                                }
+                            }
+                            for (idx, range) in reserved.iter().enumerate(){
+                                validate_range(v, &format!("{}.reserved[{}]", path, idx), &subnet, range);
+                            }
+                            if let Some(allocation_range) = allocation_range{
+                                validate_range(v, &format!("{}.allocation_range", path), &subnet, allocation_range);
+                            }
+                            subnets.ipv4.insert(name.clone(), subnet);
+                        },
+                        None if subnet.is_none() => {
+                            for (field, set) in [("gateway", gateway.is_some()), ("reserved", !reserved.is_empty()), ("allocation_range", allocation_range.is_some())]{
+                                if set{
+                                    v.error(format!("{}.{}", path, field), "requires an ipv4 subnet".to_string());
+                                }
+                            }
+                        },
+                        None => {},
+                    }
+                    let parsed6 = subnet6.as_ref().and_then(|subnet6| parse_subnet6(v, &format!("{}.subnet6", path), subnet6));
+                    match parsed6{
+                        Some(subnet6) => {
+                            if *address_mode6 == Ipv6AddressMode::Eui64 && subnet6.prefix_len() != 64{
+                                v.error(format!("{}.address_mode6", path), format!("eui64 requires a /64, {} has a /{}", subnet6, subnet6.prefix_len()));
+                            }
+                            if let Some(gateway6) = gateway6{
+                                if !usable6(&subnet6, gateway6){
+                                    v.error(format!("{}.gateway6", path), format!("{} is not a usable address of {}", gateway6, subnet6));
+                                }
+                            }
+                            subnets.ipv6.insert(name.clone(), subnet6);
+                        },
+                        None if subnet6.is_none() && gateway6.is_some() => {
+                            v.error(format!("{}.gateway6", path), "requires an ipv6 subnet6".to_string());
+                        },
+                        None => {},
+                    }
+                },
+            }
+        }
+        for (name, subnet) in &subnets.ipv4{
+            for (other_name, other) in subnets.ipv4.range::<String, _>(..name){
+                if other.contains(&subnet.network()) || subnet.contains(&other.network()){
+                    v.error(format!("networks.{}.network_type.subnet", name), format!("{} overlaps with {} of network {}", subnet, other, other_name));
+                }
+            }
+        }
+        for (name, subnet) in &subnets.ipv6{
+            for (other_name, other) in subnets.ipv6.range::<String, _>(..name){
+                if other.contains(&subnet.network()) || subnet.contains(&other.network()){
+                    v.error(format!("networks.{}.network_type.subnet6", name), format!("{} overlaps with {} of network {}", subnet, other, other_name));
+                }
+            }
+        }
+        subnets
+    }
+
+    fn validate_instances(&self, v: &mut Validator){
+        for (name, instance) in &self.instances{
+            let path = format!("instances.{}", name);
+            validate_name(v, path.clone(), name);
+            if name.len() > MAX_HOSTNAME{
+                v.error(path.clone(), format!("name is longer than {} characters and can't be used as host name", MAX_HOSTNAME));
+            }
+            if instance.vcpu == 0 || instance.vcpu > MAX_VCPU{
+                v.error(format!("{}.vcpu", path), format!("{} is out of range 1..={}", instance.vcpu, MAX_VCPU));
+            }
+            if instance.memory < Memory::from_mib(MIN_MEMORY_MIB){
+                v.error(format!("{}.memory", path), format!("{} is less than {}MiB", instance.memory, MIN_MEMORY_MIB));
+            }
+            if instance.memory > Memory::from_gib(MAX_MEMORY_GIB){
+                v.warning(format!("{}.memory", path), format!("{} is more than {}GiB, check the unit", instance.memory, MAX_MEMORY_GIB));
+            }
+            validate_cpu(v, &path, instance);
+            if instance.image.is_empty(){
+                v.error(format!("{}.image", path), "must not be empty".to_string());
+            }
+            if instance.disk.size == Some(0){
+                v.error(format!("{}.disk.size", path), "must be at least 1".to_string());
+            }
+            validate_template(v, &format!("{}.domain", path), &instance.domain.template, &instance.domain.patches);
+            let count = self.interfaces.values().filter(|interface| &interface.instance == name).count();
+            if count > MAX_INTERFACES_PER_INSTANCE{
+                v.error(path, format!("has {} interfaces, at most {} are supported", count, MAX_INTERFACES_PER_INSTANCE));
+            }
+        }
+    }
+
+    fn validate_interfaces(&self, v: &mut Validator, subnets: &Subnets){
+        let mut macs = BTreeMap::new();
+        let mut addresses: BTreeMap<(&String, IpAddr), &String> = BTreeMap::new();
+        for (name, interface) in &self.interfaces{
+            let path = format!("interfaces.{}", name);
+            validate_name(v, path.clone(), name);
+            if name.len() > MAX_LINK_NAME{
+                v.error(path.clone(), format!("name is longer than {} characters and can't be used as tap device", MAX_LINK_NAME));
+            }
+            if !self.networks.contains_key(&interface.network){
+                v.error(format!("{}.network", path), format!("unknown network '{}'", interface.network));
+            }
+            if !self.instances.contains_key(&interface.instance){
+                v.error(format!("{}.instance", path), format!("unknown instance '{}'", interface.instance));
+            }
+            if interface.mtu < MIN_MTU || interface.mtu > MAX_MTU{
+                v.error(format!("{}.mtu", path), format!("{} is out of range {}..={}", interface.mtu, MIN_MTU, MAX_MTU));
+            }
+            if let Some(mac) = interface.mac{
+                if mac.0 & 0x01 != 0{
+                    v.error(format!("{}.mac", path), format!("{} is a multicast address", mac));
+                }
+                if let Some(other) = macs.insert(mac, name){
+                    v.error(format!("{}.mac", path), format!("{} is already used by interface {}", mac, other));
+                }
+            }
+            if let Some(address) = interface.address{
+                match (subnets.ipv4.get(&interface.network), self.networks.get(&interface.network)){
+                    (Some(subnet), Some(network)) => {
+                        if !usable(subnet, &address){
+                            v.error(format!("{}.address", path), format!("{} is not a usable address of {}", address, subnet));
+                        }
+                        if let NetworkTypeConfig::Unmanaged{gateway, ..} = &network.network_type{
+                            if gateway.unwrap_or(Ipv4Addr::from(u32::from(subnet.network()) + 1)) == address{
+                                v.error(format!("{}.address", path), format!("{} is the gateway of network {}", address, interface.network));
+                            }
+                        }
+                        if let Some(other) = addresses.insert((&interface.network, address.into()), name){
+                            v.error(format!("{}.address", path), format!("{} is already used by interface {}", address, other));
+                        }
+                    },
+                    (None, Some(_)) => {
+                        v.error(format!("{}.address", path), format!("network '{}' doesn't assign ipv4 addresses", interface.network));
+                    },
+                    _ => {},
+                }
+            }
+            if let Some(address6) = interface.address6{
+                match (subnets.ipv6.get(&interface.network), self.networks.get(&interface.network)){
+                    (Some(subnet6), Some(network)) => {
+                        if !usable6(subnet6, &address6){
+                            v.error(format!("{}.address6", path), format!("{} is not a usable address of {}", address6, subnet6));
+                        }
+                        if let NetworkTypeConfig::Unmanaged{gateway6, ..} = &network.network_type{
+                            if gateway6.unwrap_or(Ipv6Addr::from(u128::from(subnet6.network()) + 1)) == address6{
+                                v.error(format!("{}.address6", path), format!("{} is the gateway of network {}", address6, interface.network));
+                            }
+                        }
+                        if let Some(other) = addresses.insert((&interface.network, address6.into()), name){
+                            v.error(format!("{}.address6", path), format!("{} is already used by interface {}", address6, other));
+                        }
+                    },
+                    (None, Some(_)) => {
+                        v.error(format!("{}.address6", path), format!("network '{}' doesn't assign ipv6 addresses", interface.network));
+                    },
+                    _ => {},
+                }
+            }
+        }
+    }
+
+    fn validate_route_tables(&self, v: &mut Validator, subnets: &Subnets){
+        for (name, route_table) in &self.route_tables{
+            let path = format!("route_tables.{}", name);
+            if !self.instances.contains_key(&route_table.instance){
+                v.error(format!("{}.instance", path), format!("unknown instance '{}'", route_table.instance));
+            }
+            // networks the route table instance is directly connected to
+            let connected: Vec<&String> = self.interfaces.values()
+                .filter(|interface| interface.instance == route_table.instance)
+                .map(|interface| &interface.network)
+                .collect();
+            for (destination, next_hops) in &route_table.routes{
+                let route_path = format!("{}.routes.{}", path, destination);
+                match self.networks.get(destination){
+                    None => v.error(route_path.clone(), format!("unknown destination network '{}'", destination)),
+                    Some(_) if !subnets.contains(destination) => {
+                        v.error(route_path.clone(), format!("destination network '{}' has no subnet", destination));
+                    },
+                    Some(_) => {},
+                }
+                if next_hops.is_empty(){
+                    v.error(route_path.clone(), "needs at least one next hop".to_string());
+                }
+                for (idx, next_hop) in next_hops.iter().enumerate(){
+                    let hop_path = format!("{}[{}]", route_path, idx);
+                    if !self.instances.contains_key(&next_hop.instance){
+                        v.error(format!("{}.instance", hop_path), format!("unknown instance '{}'", next_hop.instance));
+                    }
+                    if next_hop.instance == route_table.instance{
+                        v.error(format!("{}.instance", hop_path), "next hop must not be the instance owning the route table".to_string());
+                    }
+                    let interface = match self.interfaces.get(&next_hop.interface){
+                        Some(interface) => interface,
+                        None => {
+                            v.error(format!("{}.interface", hop_path), format!("unknown interface '{}'", next_hop.interface));
+                            continue;
+                        },
+                    };
+                    if interface.instance != next_hop.instance{
+                        v.error(format!("{}.interface", hop_path), format!("interface '{}' belongs to instance '{}', not '{}'", next_hop.interface, interface.instance, next_hop.instance));
+                    }
+                    if !subnets.contains(&interface.network){
+                        v.error(format!("{}.interface", hop_path), format!("interface '{}' is on network '{}' which has no subnet", next_hop.interface, interface.network));
+                    } else if !connected.contains(&&interface.network){
+                        v.warning(format!("{}.interface", hop_path), format!("network '{}' of interface '{}' is not directly connected to instance '{}', the next hop is skipped", interface.network, next_hop.interface, route_table.instance));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn validate_cpu(v: &mut Validator, path: &str, instance: &InstanceConfig){
+    let cpu = &instance.cpu;
+    let vcpus: BTreeSet<u32> = (0..instance.vcpu as u32).collect();
+    match (cpu.mode, &cpu.model){
+        (Some(CpuMode::HostPassthrough | CpuMode::Maximum), Some(_)) => {
+            v.error(format!("{}.cpu.model", path), format!("can't be combined with mode {}", cpu.mode.unwrap()));
+        },
+        (Some(CpuMode::Custom), None) => {
+            v.error(format!("{}.cpu.model", path), "is needed by mode custom".to_string());
+        },
+        (_, Some(model)) if model.is_empty() => {
+            v.error(format!("{}.cpu.model", path), "must not be empty".to_string());
+        },
+        _ => {},
+    }
+    if let Some(topology) = &cpu.topology{
+        let count = topology.sockets as u32 * topology.cores as u32 * topology.threads as u32;
+        if count != instance.vcpu as u32{
+            v.error(format!("{}.cpu.topology", path), format!("{} sockets * {} cores * {} threads is {} cpus, not the {} vcpus",
+                topology.sockets, topology.cores, topology.threads, count, instance.vcpu));
+        }
+    }
+    for (vcpu, cpuset) in &cpu.pinning{
+        if *vcpu >= instance.vcpu{
+            v.error(format!("{}.cpu.pinning.{}", path, vcpu), format!("the instance has only vcpus 0..{}", instance.vcpu));
+        }
+        if let Err(e) = parse_cpuset(cpuset){
+            v.error(format!("{}.cpu.pinning.{}", path, vcpu), e);
+        }
+    }
+    if !cpu.numa.is_empty(){
+        let mut assigned = BTreeSet::new();
+        let mut memory = 0;
+        for (idx, cell) in cpu.numa.iter().enumerate(){
+            let cell_path = format!("{}.cpu.numa[{}]", path, idx);
+            match parse_cpuset(&cell.cpus){
+                Ok(cpus) => {
+                    if let Some(cpu) = cpus.difference(&vcpus).next(){
+                        v.error(format!("{}.cpus", cell_path), format!("vcpu {} doesn't exist, the instance has {} vcpus", cpu, instance.vcpu));
+                    }
+                    if let Some(cpu) = cpus.intersection(&assigned).next(){
+                        v.error(format!("{}.cpus", cell_path), format!("vcpu {} is already in another cell", cpu));
+                    }
+                    assigned.extend(cpus);
+                },
+                Err(e) => v.error(format!("{}.cpus", cell_path), e),
+            }
+            if let Some(page_size) = instance.hugepages{
+                if !cell.memory.kib().is_multiple_of(page_size.kib()){
+                    v.error(format!("{}.memory", cell_path), format!("{} is not a multiple of the {} hugepages", cell.memory, page_size));
+                }
+            }
+            memory += cell.memory.kib();
+        }
+        if let Some(cpu) = vcpus.difference(&assigned).next(){
+            v.error(format!("{}.cpu.numa", path), format!("vcpu {} is in no cell", cpu));
+        }
+        if memory != instance.memory.kib(){
+            v.error(format!("{}.cpu.numa", path), format!("cells have {} together, the instance has {}", Memory::from_kib(memory), instance.memory));
+        }
+    }
+    if let Some(page_size) = instance.hugepages{
+        if page_size.kib() < MIN_PAGE_KIB || !page_size.kib().is_power_of_two(){
+            v.error(format!("{}.hugepages", path), format!("{} is not a page size", page_size));
+        } else if !instance.memory.kib().is_multiple_of(page_size.kib()){
+            v.error(format!("{}.hugepages", path), format!("memory {} is not a multiple of {}", instance.memory, page_size));
+        }
+    }
+}
+
+// names end up in domain, volume, bridge and tap names, host names and paths
+fn validate_name(v: &mut Validator, path: String, name: &str){
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'){
+        v.error(path, format!("'{}' may only contain letters, digits, '-' and '_'", name));
+    }
+}
+
+fn validate_template(v: &mut Validator, path: &str, template: &Option<String>, patches: &[XmlPatch]){
+    if template.as_ref().is_some_and(|template| template.is_empty()){
+        v.error(format!("{}.template", path), "must not be empty".to_string());
+    }
+    for (idx, patch) in patches.iter().enumerate(){
+        if let Err(e) = patch.check(){
+            v.error(format!("{}.patches[{}]", path, idx), e.to_string());
+        }
+    }
+}
+
+// cpus in libvirt cpuset syntax like 0-3,^2,8
+fn parse_cpuset(cpuset: &str) -> Result<BTreeSet<u32>, String>{
+    let mut cpus = BTreeSet::new();
+    let mut excluded = BTreeSet::new();
+    for part in cpuset.split(','){
+        let part = part.trim();
+        let (target, range) = match part.strip_prefix('^'){
+            Some(range) => (&mut excluded, range),
+            None => (&mut cpus, part),
+        };
+        let parse = |cpu: &str| cpu.parse::<u32>().map_err(|_| format!("'{}' is not a cpuset like 0-3,^2,8", cpuset));
+        match range.split_once('-'){
+            Some((start, end)) => {
+                let (start, end) = (parse(start)?, parse(end)?);
+                if start > end{
+                    return Err(format!("range {} in '{}' is reversed", range, cpuset));
+                }
+                target.extend(start..=end);
+            },
+            None => {
+                target.insert(parse(range)?);
+            },
+        }
+    }
+    let cpus: BTreeSet<u32> = cpus.difference(&excluded).copied().collect();
+    if cpus.is_empty(){
+        return Err(format!("'{}' contains no cpu", cpuset));
+    }
+    Ok(cpus)
+}
+
+fn usable(subnet: &Ipv4Net, address: &Ipv4Addr) -> bool{
+    subnet.contains(address) && *address != subnet.network() && *address != subnet.broadcast()
+}
+
+fn usable6(subnet: &Ipv6Net, address: &Ipv6Addr) -> bool{
+    subnet.contains(address) && *address != subnet.network()
+}
+
+fn validate_range(v: &mut Validator, path: &str, subnet: &Ipv4Net, range: &AddressRange){
+    for (field, address) in [("start", range.start), ("end", range.end)]{
+        if !usable(subnet, &address){
+            v.error(format!("{}.{}", path, field), format!("{} is not a usable address of {}", address, subnet));
+        }
+    }
+    if range.start > range.end{
+        v.error(path.to_string(), format!("start {} is after end {}", range.start, range.end));
+    }
+}
+
+fn parse_subnet(v: &mut Validator, path: &str, subnet: &str) -> Option<Ipv4Net>{
+    let parsed: Ipv4Net = match subnet.parse(){
+        Ok(parsed) => parsed,
+        Err(_) => {
+            v.error(path.to_string(), format!("'{}' is not a valid IPv4 CIDR", subnet));
+            return None;
+        },
+    };
+    if parsed.network() != parsed.addr(){
+        v.error(path.to_string(), format!("'{}' has host bits set, did you mean {}?", subnet, parsed.trunc()));
+    }
+    if parsed.prefix_len() > 30{
+        v.error(path.to_string(), format!("'{}' is too small, the prefix length must be 30 or less", subnet));
+    }
+    Some(parsed.trunc())
+}
+
+fn parse_subnet6(v: &mut Validator, path: &str, subnet: &str) -> Option<Ipv6Net>{
+    let parsed: Ipv6Net = match subnet.parse(){
+        Ok(parsed) => parsed,
+        Err(_) => {
+            v.error(path.to_string(), format!("'{}' is not a valid IPv6 CIDR", subnet));
+            return None;
+        },
+    };
+    if parsed.network() != parsed.addr(){
+        v.error(path.to_string(), format!("'{}' has host bits set, did you mean {}?", subnet, parsed.trunc()));
+    }
+    if parsed.prefix_len() > 126{
+        v.error(path.to_string(), format!("'{}' is too small, the prefix length must be 126 or less", subnet));
+    }
+    Some(parsed.trunc())
+}
\ No newline at end of file
@@ -1,42 +1,153 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Context};
+use pnet::util::MacAddr;
+use serde::Serialize;
 use serde_json::json;
-use virt::error::Error;
+use virt::error::{Error, ErrorNumber};
 use virt::connect::Connect;
-use crate::config::config::UserConfig;
-use crate::instance::instance::InstanceRuntime;
+use virt::domain::Domain;
+use virt::network::Network;
+use virt::storage_pool::StoragePool;
+use virt::storage_vol::StorageVol;
+use virt::stream::Stream;
+use virt::sys;
+use crate::backend::backend::{Backend, DryRun, Host, LocalHost};
+use crate::cloud_init::cloud_init::CloudInit;
+use crate::config::config::{StorageConfig, DEFAULT_POOL};
+use crate::domain::domain::{Disk, DiskDevice, DomainDef, Interface, VolumeSource};
+use crate::instance::instance::{DiskConfig, DiskMode, InstanceRuntime};
+use crate::interface::interface::InterfaceRuntime;
+use crate::network::network::{BridgeRuntime, BridgeType, NetworkMode, NetworkTypeRuntime};
+use crate::plan::plan::{Action, InstanceChange, NetworkChange, Plan};
+use crate::runtime::runtime::Runtime;
+use crate::state::state::{InstanceResources, Resources};
+use crate::xml::xml::{self, Element};
 use handlebars::Handlebars;
-use std::process::Command;
 
 pub struct VirtManager{
     pub conn: Connect,
+    host: Box<dyn Host>,
+    // virt type of the domains, the driver name, e.g. qemu or test
+    domain_type: String,
+}
+
+pub const DEFAULT_URI: &str = "qemu:///system";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Lifecycle{
+    Start,
+    // ACPI power off, waits for the guest until the timeout
+    Shutdown{
+        timeout: Duration,
+        force: bool,
+    },
+    // pulls the plug, the domain stays defined
+    Destroy,
+    Reboot,
+    Suspend,
+    Resume,
+}
+
+// live view of an instance, joined from the runtime and libvirt
+#[derive(Debug, Serialize)]
+pub struct InstanceStatus{
+    pub name: String,
+    pub domain: String,
+    pub state: String,
+    pub uptime_secs: Option<u64>,
+    pub vcpu: u32,
+    pub cpu_time_secs: f64,
+    pub memory_kib: u64,
+    pub max_memory_kib: u64,
+    // as reported by the guest balloon driver
+    pub memory_used_kib: Option<u64>,
+    pub interfaces: Vec<InterfaceStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InterfaceStatus{
+    pub name: String,
+    pub network: String,
+    pub mac: String,
+    pub tap: Option<String>,
+    // what the runtime assigned
+    pub configured: Vec<String>,
+    // what the guest actually has
+    pub addresses: Vec<String>,
+    // agent, lease or arp
+    pub source: Option<&'static str>,
+}
+
+// static address assignment of an interface on a libvirt network
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct DhcpHost{
+    pub mac: String,
+    pub ip: Ipv4Addr,
+    pub name: String,
 }
 
 impl VirtManager{
-    pub fn new() -> VirtManager{
-        let uri = "qemu:///system";
-        println!("Attempting to connect to hypervisor: '{:?}'", uri);
-        let conn = match Connect::open(uri) {
-            Ok(c) => c,
-            Err(e) => panic!("No connection to hypervisor: {} ", e),
-        };
-        VirtManager{
+    pub fn new(uri: &str) -> anyhow::Result<VirtManager>{
+        VirtManager::with_host(uri, Box::new(LocalHost))
+    }
+
+    // bridges, taps and seed isos are set up through the given host
+    pub fn with_host(uri: &str, host: Box<dyn Host>) -> anyhow::Result<VirtManager>{
+        let conn = Connect::open(uri).with_context(|| format!("failed to connect to hypervisor {}", uri))?;
+        let domain_type = conn.get_type()?.to_lowercase();
+        println!("Connected to hypervisor at '{}'", conn.get_uri()?);
+        Ok(VirtManager{
             conn,
-        }
+            host,
+            domain_type,
+        })
     }
-    pub fn connect(&mut self) {
-        match self.conn.get_uri() {
-            Ok(u) => println!("Connected to hypervisor at '{}'", u),
-            Err(e) => {
-                self.disconnect();
-                panic!("Failed to get URI for hypervisor connection: {}", e);
-            }
-        };
+
+    // only looks at the hypervisor, for a dry run. the host is a dry run as well
+    pub fn read_only(uri: &str) -> anyhow::Result<VirtManager>{
+        let conn = Connect::open_read_only(uri).with_context(|| format!("failed to connect to hypervisor {}", uri))?;
+        let domain_type = conn.get_type()?.to_lowercase();
+        Ok(VirtManager{
+            conn,
+            host: Box::new(DryRun::new()),
+            domain_type,
+        })
     }
-    pub fn disconnect(&mut self) {
-        if let Err(e) = self.conn.close() {
-            panic!("Failed to disconnect from hypervisor: {}", e);
-        }
+
+    pub fn disconnect(&mut self) -> anyhow::Result<()> {
+        self.conn.close().context("failed to disconnect from hypervisor")?;
         println!("Disconnected from hypervisor");
+        Ok(())
+    }
+
+    // without a host in the uri the hypervisor runs on this machine, like qemu:///system,
+    // qemu+ssh://host/system is another one
+    pub fn is_local(&self) -> anyhow::Result<bool> {
+        let uri = self.conn.get_uri()?;
+        Ok(uri.split_once("://").is_none_or(|(_, rest)| rest.starts_with('/')))
+    }
+
+    // bridges and taps are set up on this machine, they have to be where the domains run
+    pub fn check_local(&self, runtime: &Runtime, resources: &Resources) -> anyhow::Result<()> {
+        if self.is_local()?{
+            return Ok(());
+        }
+        let bridges = runtime.networks.iter()
+            .filter(|(_, network)| matches!(network.network_type, NetworkTypeRuntime::Unmanaged{..}))
+            .map(|(name, _)| name)
+            .chain(resources.bridges.keys());
+        if let Some(name) = bridges.min(){
+            return Err(anyhow!("network {} needs a bridge on the hypervisor host, which is only set up for a local hypervisor and not for {}",
+                name, self.conn.get_uri()?));
+        }
+        Ok(())
+    }
+
+    // the qemu pid files and processes of the domains are on this host
+    fn is_local_system(&self) -> bool {
+        self.conn.get_uri().is_ok_and(|uri| uri == LOCAL_SYSTEM_URI)
     }
 
     pub fn show_hypervisor_info(&self) -> Result<(), Error> {
@@ -56,153 +167,1104 @@ impl VirtManager{
         Err(Error::last_error())
     }
 
-    pub fn create_instance(&self, instances: HashMap<String,InstanceRuntime>, user_config: Option<UserConfig>) -> anyhow::Result<()> {
+    // removed networks are dropped from resources
+    pub fn destroy_networks(&self, topology: &str, resources: &mut Resources) -> anyhow::Result<()> {
+        let names: Vec<String> = resources.bridges.keys().chain(resources.libvirt_networks.keys()).cloned().collect();
+        for name in names{
+            self.destroy_network(&name, topology, resources)?;
+        }
+        Ok(())
+    }
+
+    pub fn destroy_network(&self, name: &str, topology: &str, resources: &mut Resources) -> anyhow::Result<()> {
+        if let Some(bridge) = resources.bridges.get(name){
+            if destroy_bridge(self.host(), bridge, topology)?{
+                println!("network {} removed bridge {}", name, bridge.name);
+            }
+            resources.bridges.remove(name);
+        }
+        if let Some(libvirt_name) = resources.libvirt_networks.get(name){
+            if let Some(libvirt_network) = self.lookup_network(libvirt_name)?{
+                if libvirt_network.is_active()?{
+                    libvirt_network.destroy()?;
+                }
+                libvirt_network.undefine()?;
+                println!("network {} removed libvirt network {}", name, libvirt_name);
+            }
+            resources.libvirt_networks.remove(name);
+        }
+        Ok(())
+    }
+
+    // adds and removes dhcp host entries of a libvirt network without restarting it
+    fn update_network(&self, name: &str, runtime: &Runtime, change: &NetworkChange) -> anyhow::Result<()> {
+        let libvirt_name = match runtime.networks.get(name).map(|network| &network.network_type){
+            Some(NetworkTypeRuntime::Libvirt{name, ..}) => name,
+            _ => return Ok(()),
+        };
+        let libvirt_network = self.lookup_network(libvirt_name)?.ok_or(anyhow!("libvirt network {} not found", libvirt_name))?;
+        let flags = sys::VIR_NETWORK_UPDATE_AFFECT_LIVE | sys::VIR_NETWORK_UPDATE_AFFECT_CONFIG;
+        for host in &change.hosts_removed{
+            libvirt_network.update(sys::VIR_NETWORK_UPDATE_COMMAND_DELETE, sys::VIR_NETWORK_SECTION_IP_DHCP_HOST, -1, &dhcp_host_xml(host), flags)
+                .with_context(|| format!("failed to remove dhcp host {} from libvirt network {}", host.ip, libvirt_name))?;
+        }
+        for host in &change.hosts_added{
+            libvirt_network.update(sys::VIR_NETWORK_UPDATE_COMMAND_ADD_LAST, sys::VIR_NETWORK_SECTION_IP_DHCP_HOST, -1, &dhcp_host_xml(host), flags)
+                .with_context(|| format!("failed to add dhcp host {} to libvirt network {}", host.ip, libvirt_name))?;
+        }
+        println!("network {} updated libvirt network {}", name, libvirt_name);
+        Ok(())
+    }
+
+    pub fn dhcp_hosts(name: &str, runtime: &Runtime) -> Vec<DhcpHost> {
+        let mut hosts = Vec::new();
+        for (instance_name, instance) in &runtime.instances{
+            for interface in instance.interfaces.values(){
+                if let (true, Some(address)) = (interface.network == name, interface.address){
+                    hosts.push(DhcpHost{
+                        mac: interface.mac.to_string(),
+                        ip: address,
+                        name: instance_name.clone(),
+                    });
+                }
+            }
+        }
+        hosts
+    }
+
+    pub fn network_xml(name: &str, runtime: &Runtime) -> anyhow::Result<String> {
+        let network = runtime.networks.get(name).ok_or(anyhow!("network {} not found", name))?;
+        let (libvirt_name, mode, subnet, gateway, dhcp_range, domain) = match &network.network_type{
+            NetworkTypeRuntime::Libvirt{name, mode, subnet, gateway, dhcp_range, domain, ..} => (name, mode, subnet, gateway, dhcp_range, domain),
+            _ => return Err(anyhow!("network {} is not created by virt-rs", name)),
+        };
+        let hosts = VirtManager::dhcp_hosts(name, runtime);
+        let forward = match mode{
+            NetworkMode::Nat => Some("nat"),
+            NetworkMode::Route => Some("route"),
+            NetworkMode::Isolated => None,
+        };
         let reg = Handlebars::new();
-        let xml = format!("{}",reg.render_template(DOMAIN_DEV, &instances)?);
-        println!("{}", xml);
-        for (name, instance) in instances{
-            std::fs::copy(instance.image, format!("/var/lib/libvirt/images/{}.img", name))?;
-        }
-        if let Some(user_config) = user_config{
-            std::fs::remove_file("/var/lib/libvirt/images/cidata.iso").ok();
-            let key = std::fs::read_to_string(user_config.key_path)?;
-            let key = key.trim();
-            let user_data = format!("{}",reg.render_template(USER_DATA, &json!({"user_name": user_config.user_name, "key": key}))?);
-            std::fs::write("/var/lib/libvirt/images/user-data", user_data.clone())?;
-            std::fs::write("/var/lib/libvirt/images/meta-data", "")?;
-            println!("{}", user_data);
-            let mut cmd = Command::new("/usr/bin/genisoimage");
-            cmd.arg("-output")
-                .arg("/var/lib/libvirt/images/cidata.iso")
-                .arg("-volid")
-                .arg("cidata")
-                .arg("-input-charset")
-                .arg("utf-8")
-                .arg("-joliet")
-                .arg("-rock")
-                .arg("/var/lib/libvirt/images/meta-data")
-                .arg("/var/lib/libvirt/images/user-data");
-            cmd.output()?;
-            //genisoimage -output ci.iso -quiet -volid cidata -input-charset utf-8 -joliet -rock user-data meta-data network-config
-        }
-        virt::domain::Domain::create_xml(&self.conn, &xml, 0)?;
-        Ok(())
-    }
-}
-
-const USER_DATA: &str = r#"#cloud-config
-hostname: cl-ubuntu
-fqdn: cl-ubuntu
-package_update: false
-package_upgrade: false
-ssh_pwauth: true
-disable_root: false
-users:
-  - default
-  - name: ubuntu
-    shell: /bin/bash
-    sudo: ALL=(ALL) NOPASSWD:ALL
-    lock_passwd: false
-    ssh-authorized-keys:
-      - {{ key }}
-  - name: {{ user_name }}
-    shell: /bin/bash
-    sudo: ALL=(ALL) NOPASSWD:ALL
-    lock_passwd: false
-    ssh-authorized-keys:
-      - {{ key }}
-"#;
+        let xml = reg.render_template(NETWORK_DEV, &json!({
+            "name": libvirt_name,
+            "forward": forward,
+            "domain": domain,
+            "gateway": gateway,
+            "netmask": subnet.netmask(),
+            "dhcp_range": dhcp_range,
+            "hosts": hosts,
+        }))?;
+        Ok(xml)
+    }
+
+    // destroyed instances and removed networks are dropped from resources, whatever is left failed
+    pub fn destroy_topology(&self, topology: &str, resources: &mut Resources, timeout: Duration) -> anyhow::Result<BTreeMap<String, anyhow::Result<()>>> {
+        let mut results = BTreeMap::new();
+        for (name, instance) in resources.instances.clone(){
+            let result = self.destroy_instance(&name, &instance, timeout);
+            match &result{
+                Ok(_) => {
+                    resources.instances.remove(&name);
+                    println!("instance {} destroyed", name);
+                },
+                Err(e) => println!("instance {} failed: {:#}", name, e),
+            }
+            results.insert(name, result);
+        }
+        self.destroy_networks(topology, resources)?;
+        Ok(results)
+    }
+
+    // executes a plan, instances are removed before and created after the networks they use
+    pub fn apply(&self, plan: &Plan, runtime: &Runtime, resources: &mut Resources, timeout: Duration) -> anyhow::Result<BTreeMap<String, anyhow::Result<()>>> {
+        let mut results = BTreeMap::new();
+        for (name, change) in &plan.instances{
+            if !matches!(change.action, Action::Destroy | Action::Replace){
+                continue;
+            }
+            if let Some(instance) = resources.instances.get(name).cloned(){
+                let result = self.destroy_instance(name, &instance, timeout);
+                match &result{
+                    Ok(_) => {
+                        resources.instances.remove(name);
+                        println!("instance {} destroyed", name);
+                    },
+                    Err(e) => {
+                        println!("instance {} failed: {:#}", name, e);
+                        results.insert(name.clone(), result);
+                    },
+                }
+            }
+        }
+        for (name, change) in &plan.networks{
+            match change.action{
+                Action::Destroy | Action::Replace => self.destroy_network(name, &plan.topology, resources)?,
+                _ => {},
+            }
+            match change.action{
+                Action::Create | Action::Replace => self.create_network(name, runtime, resources)?,
+                Action::Update => self.update_network(name, runtime, change)?,
+                Action::Destroy => {},
+            }
+        }
+        for (name, change) in &plan.instances{
+            if results.contains_key(name){
+                continue;
+            }
+            let instance = match runtime.instances.get(name){
+                Some(instance) => instance,
+                None => {
+                    results.insert(name.clone(), Ok(()));
+                    continue;
+                },
+            };
+            let result = match change.action{
+                Action::Create | Action::Replace => {
+                    self.check_owner(name, instance).and_then(|_| {
+                        resources.instances.insert(name.clone(), VirtManager::instance_resources(name, instance, &runtime.storage));
+                        self.create_instance(name, instance, runtime)
+                    })
+                },
+                Action::Update => {
+                    let result = self.update_instance(name, instance, change, runtime, timeout);
+                    // a failed update keeps the resources of what was deployed before
+                    if result.is_ok(){
+                        resources.instances.insert(name.clone(), VirtManager::instance_resources(name, instance, &runtime.storage));
+                    }
+                    result
+                },
+                Action::Destroy => Ok(()),
+            };
+            match &result{
+                Ok(_) if change.action == Action::Update => println!("instance {} updated", name),
+                Ok(_) => println!("instance {} created", name),
+                Err(e) => println!("instance {} failed: {:#}", name, e),
+            }
+            results.insert(name.clone(), result);
+        }
+        Ok(results)
+    }
+
+    fn update_instance(&self, name: &str, instance: &InstanceRuntime, change: &InstanceChange, runtime: &Runtime, timeout: Duration) -> anyhow::Result<()> {
+        let domain = self.lookup_domain(name)?.ok_or(anyhow!("instance {} is not defined", name))?;
+        if change.autostart{
+            domain.set_autostart(instance.autostart)?;
+        }
+        if change.redefine{
+            return self.redefine_instance(name, &domain, instance, change, runtime, timeout);
+        }
+        // earlier hot-plugs may have used up the spare root ports the plan counted on
+        if let Some(free) = free_root_ports(&domain.get_xml_desc(0)?)?{
+            if change.interfaces_added.len() > free + change.interfaces_removed.len(){
+                // defining a transient domain would make it persistent
+                if !instance.persistent{
+                    return Err(anyhow!("instance {} has {} free hot-plug ports for {} interfaces and is transient, it can only be recreated with down and up",
+                        name, free, change.interfaces_added.len()));
+                }
+                println!("instance {} has {} free hot-plug ports for {} interfaces", name, free, change.interfaces_added.len());
+                return self.redefine_instance(name, &domain, instance, change, runtime, timeout);
+            }
+        }
+        let flags = device_flags(&domain, instance.persistent)?;
+        for (interface_name, interface) in &change.interfaces_removed{
+            let xml = VirtManager::interface_xml(interface_name, interface)?;
+            domain.detach_device_flags(&xml, flags)
+                .with_context(|| format!("failed to unplug interface {}", interface_name))?;
+            // the guest releases the device asynchronously, a replugged interface would reuse its mac
+            if flags & sys::VIR_DOMAIN_AFFECT_LIVE != 0 && !wait_for_detach(&domain, interface.mac, timeout)?{
+                return Err(anyhow!("interface {} was not unplugged by the guest within {}s", interface_name, timeout.as_secs()));
+            }
+            println!("instance {} unplugged interface {}", name, interface_name);
+        }
+        for (interface_name, interface) in &change.interfaces_added{
+            let xml = VirtManager::interface_xml(interface_name, interface)?;
+            domain.attach_device_flags(&xml, flags)
+                .with_context(|| format!("failed to plug interface {}", interface_name))?;
+            println!("instance {} plugged interface {}", name, interface_name);
+        }
+        if change.guest_config{
+            self.create_seed_iso(name, instance, runtime)?;
+            if change.restart && domain.is_active()?{
+                // the guest applies changed addresses and routes only on boot
+                self.shutdown_domain(name, &domain, timeout)?;
+                domain.create()?;
+            } else if domain.is_active()?{
+                // qemu keeps the old seed iso open, ejecting and inserting it again makes the new one visible
+                domain.update_device_flags(&VirtManager::seed_iso_xml(name, instance, None), sys::VIR_DOMAIN_AFFECT_LIVE)?;
+                domain.update_device_flags(&VirtManager::seed_iso_xml(name, instance, Some(&runtime.storage)), sys::VIR_DOMAIN_AFFECT_LIVE)?;
+            }
+        }
+        Ok(())
+    }
+
+    // the new definition replaces the old one while the domain is stopped, it already has all
+    // interfaces so nothing is hot-plugged, the disk stays as it is
+    fn redefine_instance(&self, name: &str, domain: &Domain, instance: &InstanceRuntime, change: &InstanceChange, runtime: &Runtime, timeout: Duration) -> anyhow::Result<()> {
+        let active = domain.is_active()?;
+        if active{
+            self.shutdown_domain(name, domain, timeout)?;
+        }
+        if change.guest_config{
+            self.create_seed_iso(name, instance, runtime)?;
+        }
+        let xml = VirtManager::domain_xml(name, instance, &runtime.storage, self.domain_type())?;
+        let domain = Domain::define_xml(&self.conn, &xml).with_context(|| format!("failed to redefine instance {}", name))?;
+        domain.set_autostart(instance.autostart)?;
+        if active{
+            domain.create()?;
+        }
+        println!("instance {} redefined", name);
+        Ok(())
+    }
+
+    pub fn destroy_instance(&self, name: &str, instance: &InstanceResources, timeout: Duration) -> anyhow::Result<()> {
+        if let Some(domain) = self.lookup_domain(&instance.domain)?{
+            // a domain of the same name from somewhere else, its volumes and taps are left alone as well
+            let uuid = domain.get_uuid_string()?;
+            if instance.uuid.as_ref().is_some_and(|expected| *expected != uuid){
+                println!("instance {} skipped, domain {} has uuid {} and belongs to something else", name, instance.domain, uuid);
+                return Ok(());
+            }
+            if domain.is_active()?{
+                self.shutdown_domain(name, &domain, timeout)?;
+            }
+            // transient domains are gone once they are stopped, persistent ones have to be undefined
+            if let Some(domain) = self.lookup_domain(&instance.domain)?{
+                domain.undefine_flags(sys::VIR_DOMAIN_UNDEFINE_MANAGED_SAVE | sys::VIR_DOMAIN_UNDEFINE_NVRAM)?;
+            }
+        }
+        if let Some(pool) = &instance.pool{
+            self.delete_volumes(pool, &instance.volumes)?;
+        }
+        // the serial logs are written on the hypervisor host
+        if self.is_local()?{
+            for file in &instance.files{
+                self.host().remove_path(file)?;
+            }
+        }
+        for tap in &instance.taps{
+            remove_tap(self.host(), tap)?;
+        }
+        Ok(())
+    }
+
+    // the host resources create_instance makes for an instance
+    pub fn instance_resources(name: &str, instance: &InstanceRuntime, storage: &StorageConfig) -> InstanceResources{
+        let taps = instance.interfaces.iter()
+            .filter(|(_, interface)| interface.bridge.is_some())
+            .map(|(interface_name, _)| interface_name.clone())
+            .collect();
+        InstanceResources{
+            domain: name.to_string(),
+            uuid: Some(instance.uuid.clone()),
+            pool: Some(storage.pool.clone()),
+            volumes: vec![disk_volume(name), seed_iso_volume(name)],
+            files: vec![serial_log_path(name)],
+            taps,
+        }
+    }
+
+    fn shutdown_domain(&self, name: &str, domain: &Domain, timeout: Duration) -> anyhow::Result<()> {
+        // ask the guest to power off via ACPI first and only pull the plug if it doesn't react in time
+        if domain.shutdown().is_ok(){
+            if wait_for_shutdown(domain, timeout)?{
+                return Ok(());
+            }
+            println!("instance {} did not shut down within {}s, destroying it", name, timeout.as_secs());
+        }
+        if domain.is_active()?{
+            domain.destroy()?;
+        }
+        Ok(())
+    }
+
+    pub fn lifecycle(&self, name: &str, domain_name: &str, persistent: bool, operation: Lifecycle) -> anyhow::Result<()> {
+        let domain = self.lookup_domain(domain_name)?.ok_or(anyhow!("instance {} is not defined", name))?;
+        let (state, _) = domain.get_state()?;
+        // a transient domain is gone once it stops, only up or apply can bring it back
+        let gone = if persistent { "" } else { ", it is transient and no longer defined" };
+        match operation{
+            Lifecycle::Start => {
+                match state{
+                    sys::VIR_DOMAIN_SHUTOFF | sys::VIR_DOMAIN_CRASHED => {
+                        domain.create()?;
+                        println!("instance {} started", name);
+                    },
+                    sys::VIR_DOMAIN_PAUSED => return Err(anyhow!("instance {} is suspended, resume it instead", name)),
+                    _ => println!("instance {} already running", name),
+                }
+            },
+            Lifecycle::Shutdown{timeout, force} => {
+                if !domain.is_active()?{
+                    println!("instance {} already shut off", name);
+                    return Ok(());
+                }
+                if force{
+                    self.shutdown_domain(name, &domain, timeout)?;
+                } else {
+                    domain.shutdown()?;
+                    if !wait_for_shutdown(&domain, timeout)?{
+                        return Err(anyhow!("instance {} did not shut down within {}s", name, timeout.as_secs()));
+                    }
+                }
+                println!("instance {} shut down{}", name, gone);
+            },
+            Lifecycle::Destroy => {
+                if domain.is_active()?{
+                    domain.destroy()?;
+                    println!("instance {} destroyed{}", name, gone);
+                } else {
+                    println!("instance {} already shut off", name);
+                }
+            },
+            Lifecycle::Reboot => {
+                if state != sys::VIR_DOMAIN_RUNNING{
+                    return Err(anyhow!("instance {} is {}, only running instances can be rebooted", name, state_name(state)));
+                }
+                domain.reboot(0)?;
+                println!("instance {} rebooting", name);
+            },
+            Lifecycle::Suspend => {
+                match state{
+                    sys::VIR_DOMAIN_PAUSED => println!("instance {} already suspended", name),
+                    sys::VIR_DOMAIN_RUNNING | sys::VIR_DOMAIN_BLOCKED => {
+                        domain.suspend()?;
+                        println!("instance {} suspended", name);
+                    },
+                    _ => return Err(anyhow!("instance {} is {}, only running instances can be suspended", name, state_name(state))),
+                }
+            },
+            Lifecycle::Resume => {
+                if state != sys::VIR_DOMAIN_PAUSED{
+                    println!("instance {} is not suspended", name);
+                    return Ok(());
+                }
+                domain.resume()?;
+                println!("instance {} resumed", name);
+            },
+        }
+        Ok(())
+    }
+
+    pub fn instance_status(&self, name: &str, domain_name: &str, instance: &InstanceRuntime, runtime: &Runtime) -> anyhow::Result<InstanceStatus> {
+        let mut status = InstanceStatus{
+            name: name.to_string(),
+            domain: domain_name.to_string(),
+            state: "not defined".to_string(),
+            uptime_secs: None,
+            vcpu: 0,
+            cpu_time_secs: 0.0,
+            memory_kib: 0,
+            max_memory_kib: 0,
+            memory_used_kib: None,
+            interfaces: Vec::new(),
+        };
+        let domain = self.lookup_domain(domain_name)?;
+        let mut live_addresses = BTreeMap::new();
+        let mut taps = BTreeMap::new();
+        let mut source = None;
+        if let Some(domain) = &domain{
+            let info = domain.get_info()?;
+            status.state = state_name(info.state).to_string();
+            status.vcpu = info.nr_virt_cpu;
+            status.cpu_time_secs = info.cpu_time as f64 / 1e9;
+            status.memory_kib = info.memory;
+            status.max_memory_kib = info.max_mem;
+            if domain.is_active()?{
+                if self.is_local_system(){
+                    status.uptime_secs = domain_uptime(domain_name);
+                }
+                status.memory_used_kib = memory_used(domain);
+                (source, live_addresses) = self.interface_addresses(domain);
+                taps = interface_targets(&domain.get_xml_desc(0)?)?;
+            }
+        }
+        for (interface_name, interface) in &instance.interfaces{
+            let mac = interface.mac.to_string();
+            let network = runtime.networks.get(&interface.network);
+            let mut configured = Vec::new();
+            if let (Some(address), Some(subnet)) = (interface.address, network.and_then(|network| network.subnet())){
+                configured.push(format!("{}/{}", address, subnet.prefix_len()));
+            }
+            if let (Some(address), Some(subnet)) = (interface.address6, network.and_then(|network| network.subnet6())){
+                configured.push(format!("{}/{}", address, subnet.prefix_len()));
+            }
+            let addresses: Vec<String> = live_addresses.remove(&mac).unwrap_or_default();
+            status.interfaces.push(InterfaceStatus{
+                name: interface_name.clone(),
+                network: interface.network.clone(),
+                tap: taps.remove(&mac),
+                mac,
+                configured,
+                source: if addresses.is_empty() { None } else { source },
+                addresses,
+            });
+        }
+        Ok(status)
+    }
 
+    // addresses keyed by mac, the guest agent knows them best, dhcp leases and the arp table are the fallback
+    fn interface_addresses(&self, domain: &Domain) -> (Option<&'static str>, BTreeMap<String, Vec<String>>) {
+        let sources = [
+            (sys::VIR_DOMAIN_INTERFACE_ADDRESSES_SRC_AGENT, "agent"),
+            (sys::VIR_DOMAIN_INTERFACE_ADDRESSES_SRC_LEASE, "lease"),
+            (sys::VIR_DOMAIN_INTERFACE_ADDRESSES_SRC_ARP, "arp"),
+        ];
+        for (source, source_name) in sources{
+            let interfaces = match domain.interface_addresses(source, 0){
+                Ok(interfaces) => interfaces,
+                Err(_) => continue,
+            };
+            let mut addresses: BTreeMap<String, Vec<String>> = BTreeMap::new();
+            for interface in interfaces{
+                for address in interface.addrs{
+                    addresses.entry(interface.hwaddr.to_lowercase()).or_default().push(format!("{}/{}", address.addr, address.prefix));
+                }
+            }
+            if !addresses.is_empty(){
+                return (Some(source_name), addresses);
+            }
+        }
+        (None, BTreeMap::new())
+    }
+
+    fn lookup_network(&self, name: &str) -> anyhow::Result<Option<Network>> {
+        match Network::lookup_by_name(&self.conn, name){
+            Ok(network) => Ok(Some(network)),
+            Err(e) if e.code() == ErrorNumber::NoNetwork => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn lookup_domain(&self, name: &str) -> anyhow::Result<Option<Domain>> {
+        match Domain::lookup_by_name(&self.conn, name){
+            Ok(domain) => Ok(Some(domain)),
+            Err(e) if e.code() == ErrorNumber::NoDomain => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn domain_def(name: &str, instance: &InstanceRuntime, storage: &StorageConfig, domain_type: &str) -> anyhow::Result<DomainDef> {
+        DomainDef::builder(name, instance)
+            .domain_type(domain_type)
+            .disk(&storage.pool, &disk_volume(name))
+            .cdrom(Some(VolumeSource::new(&storage.pool, &seed_iso_volume(name))))
+            .serial_log(&serial_log_path(name))
+            .build()
+    }
+
+    // the built-in domain or the template of the instance, with its patches applied
+    pub fn domain_xml(name: &str, instance: &InstanceRuntime, storage: &StorageConfig, domain_type: &str) -> anyhow::Result<String> {
+        let domain = VirtManager::domain_def(name, instance, storage, domain_type)?;
+        let mut xml = match &instance.domain.template{
+            Some(template) => domain_template(template, name, instance, storage, &domain)
+                .with_context(|| format!("failed to render template {} of instance {}", template, name))?,
+            None => Element::from(&domain),
+        };
+        for patch in &instance.domain.patches{
+            patch.apply(&mut xml).with_context(|| format!("failed to patch the domain xml of instance {}", name))?;
+        }
+        Ok(xml.to_string())
+    }
+
+    // used on its own to hot-plug and unplug an interface
+    pub fn interface_xml(name: &str, interface: &InterfaceRuntime) -> anyhow::Result<String> {
+        let interface = Interface::new(name, interface)?;
+        Ok(Element::from(&interface).to_string())
+    }
+
+    // swaps the medium of the seed iso drive, without storage the drive is ejected
+    fn seed_iso_xml(name: &str, instance: &InstanceRuntime, storage: Option<&StorageConfig>) -> String {
+        let source = storage.map(|storage| VolumeSource::new(&storage.pool, &seed_iso_volume(name)));
+        Element::from(&Disk::cdrom(&instance.domain, source)).to_string()
+    }
+
+    // the pool outlives the topology, it usually holds the images as well
+    fn storage_pool(&self, storage: &StorageConfig) -> anyhow::Result<StoragePool> {
+        let pool = match self.lookup_pool(&storage.pool)?{
+            Some(pool) => pool,
+            None => {
+                let path = match (&storage.path, storage.pool.as_str()){
+                    (Some(path), _) => path.as_str(),
+                    (None, DEFAULT_POOL) => IMAGE_DIR,
+                    (None, _) => return Err(anyhow!("storage pool {} does not exist, set storage.path to create it", storage.pool)),
+                };
+                let xml = templates()?.render("pool", &json!({
+                    "name": storage.pool,
+                    "path": path,
+                }))?;
+                let pool = StoragePool::define_xml(&self.conn, &xml, 0)?;
+                // creates the directory
+                pool.build(0)?;
+                pool.set_autostart(true)?;
+                println!("storage pool {} defined at {}", storage.pool, path);
+                pool
+            },
+        };
+        if !pool.is_active()?{
+            pool.create(0)?;
+        }
+        // images copied into the directory behind the back of libvirt are only found after a refresh
+        pool.refresh(0)?;
+        Ok(pool)
+    }
+
+    fn lookup_pool(&self, name: &str) -> anyhow::Result<Option<StoragePool>> {
+        match StoragePool::lookup_by_name(&self.conn, name){
+            Ok(pool) => Ok(Some(pool)),
+            Err(e) if e.code() == ErrorNumber::NoStoragePool => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
 
-const DOMAIN_DEV: &str = r#"
-{{#each this as |instance|}}
-<domain type="qemu">
-  <name>{{ @key }}</name>
-  <uuid>8634a4a3-a491-43d4-85c6-5e47489ee0ea</uuid>
-  <metadata>
-    <libosinfo:libosinfo xmlns:libosinfo="http://libosinfo.org/xmlns/libvirt/domain/1.0">
-      <libosinfo:os id="http://ubuntu.com/ubuntu/23.10"/>
-    </libosinfo:libosinfo>
-  </metadata>
-  <memory unit='GiB'>{{ instance.memory }}</memory>
-  <currentMemory unit='GiB'>{{ instance.memory }}</currentMemory>
-  <vcpu>{{ instance.vcpu }}</vcpu>
-  <os>
-    <type arch="x86_64" machine="q35">hvm</type>
-    <boot dev="hd"/>
-  </os>
-  <features>
-    <acpi/>
-    <apic/>
-  </features>
-  <clock offset="utc">
-    <timer name="rtc" tickpolicy="catchup"/>
-    <timer name="pit" tickpolicy="delay"/>
-    <timer name="hpet" present="no"/>
-  </clock>
-  <pm>
-    <suspend-to-mem enabled="no"/>
-    <suspend-to-disk enabled="no"/>
-  </pm>
-  <devices>
-    <emulator>/usr/bin/qemu-system-x86_64</emulator>
-    <disk type="file" device="disk">
-      <driver name="qemu" type="qcow2"/>
-      <source file="/var/lib/libvirt/images/{{ @key }}.img"/>
-      <target dev="vda" bus="virtio"/>
-    </disk>
-    <controller type="usb" model="qemu-xhci" ports="15"/>
-    <controller type="pci" model="pcie-root"/>
-    <controller type="pci" model="pcie-root-port"/>
-    <controller type="pci" model="pcie-root-port"/>
-    <controller type="pci" model="pcie-root-port"/>
-    <controller type="pci" model="pcie-root-port"/>
-    <controller type="pci" model="pcie-root-port"/>
-    <controller type="pci" model="pcie-root-port"/>
-    <controller type="pci" model="pcie-root-port"/>
-    <controller type="pci" model="pcie-root-port"/>
-    <controller type="pci" model="pcie-root-port"/>
-    <controller type="pci" model="pcie-root-port"/>
-    <controller type="pci" model="pcie-root-port"/>
-    <controller type="pci" model="pcie-root-port"/>
-    <controller type="pci" model="pcie-root-port"/>
-    <controller type="pci" model="pcie-root-port"/>
-    {{#each interfaces as |interface|}}
-    {{#if interface.managed}}
-    <interface type='network'>
-        <source network='{{interface.managed}}'/>
-        <model type='virtio'/>
-    {{else}}
-    <interface type='ethernet'>
-        <target dev='{{@key}}'/>
-        <model type='virtio'/>
+    fn lookup_volume(pool: &StoragePool, name: &str) -> anyhow::Result<Option<StorageVol>> {
+        match StorageVol::lookup_by_name(pool, name){
+            Ok(volume) => Ok(Some(volume)),
+            Err(e) if e.code() == ErrorNumber::NoStorageVolume => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // images are given as volume name in the topology pool or as path of a volume in any pool
+    fn image_volume(&self, pool: &StoragePool, image: &str) -> anyhow::Result<StorageVol> {
+        let volume = if image.contains('/'){
+            StorageVol::lookup_by_path(&self.conn, image)
+        } else {
+            StorageVol::lookup_by_name(pool, image)
+        };
+        volume.with_context(|| format!("image {} is not a volume of a storage pool, copy it into the directory of pool {}", image, pool.get_name().unwrap_or_default()))
+    }
+
+    fn upload(&self, volume: &StorageVol, data: &[u8]) -> anyhow::Result<()> {
+        let stream = Stream::new(&self.conn, 0)?;
+        volume.upload(&stream, 0, data.len() as u64, 0)?;
+        let mut sent = 0;
+        while sent < data.len(){
+            sent += stream.send(&data[sent..])?;
+        }
+        stream.finish()?;
+        Ok(())
+    }
+
+    fn delete_volumes(&self, pool: &str, volumes: &[String]) -> anyhow::Result<()> {
+        let pool = match self.lookup_pool(pool)?{
+            Some(pool) => pool,
+            None => return Ok(()),
+        };
+        for volume in volumes{
+            if let Some(volume) = VirtManager::lookup_volume(&pool, volume)?{
+                volume.delete(0)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Backend for VirtManager{
+    fn host(&self) -> &dyn Host{
+        self.host.as_ref()
+    }
+
+    fn domain_type(&self) -> &str{
+        &self.domain_type
+    }
+
+    fn report(&self, message: &str){
+        println!("{}", message);
+    }
+
+    fn network_active(&self, name: &str) -> anyhow::Result<Option<bool>>{
+        match self.lookup_network(name)?{
+            Some(network) => Ok(Some(network.is_active()?)),
+            None => Ok(None),
+        }
+    }
+
+    fn define_network(&self, _name: &str, xml: &str) -> anyhow::Result<()>{
+        Network::define_xml(&self.conn, xml)?;
+        Ok(())
+    }
+
+    fn start_network(&self, name: &str) -> anyhow::Result<()>{
+        let network = self.lookup_network(name)?.ok_or(anyhow!("libvirt network {} not found", name))?;
+        network.create()?;
+        Ok(())
+    }
+
+    fn domain_active(&self, name: &str) -> anyhow::Result<Option<bool>>{
+        match self.lookup_domain(name)?{
+            Some(domain) => Ok(Some(domain.is_active()?)),
+            None => Ok(None),
+        }
+    }
+
+    fn domain_uuid(&self, name: &str) -> anyhow::Result<Option<String>>{
+        match self.lookup_domain(name)?{
+            Some(domain) => Ok(Some(domain.get_uuid_string()?)),
+            None => Ok(None),
+        }
+    }
+
+    fn create_disk(&self, storage: &StorageConfig, volume: &str, image: &str, disk: DiskConfig) -> anyhow::Result<()>{
+        let pool = self.storage_pool(storage)?;
+        let image_volume = self.image_volume(&pool, image)?;
+        let image_size = image_volume.get_info()?.capacity;
+        let capacity = match disk.size{
+            Some(size) => {
+                let bytes = size as u64 * 1024 * 1024 * 1024;
+                if bytes < image_size{
+                    return Err(anyhow!("disk size {}GiB of volume {} is smaller than image {} with {}GiB",
+                        size, volume, image, image_size.div_ceil(1024 * 1024 * 1024)));
+                }
+                bytes
+            },
+            None => image_size,
+        };
+        let image_format = volume_format(&image_volume.get_xml_desc(0)?)?.unwrap_or("raw".to_string());
+        // leftover of an instance that failed to come up
+        if let Some(leftover) = VirtManager::lookup_volume(&pool, volume)?{
+            leftover.delete(0)?;
+        }
+        let reg = templates()?;
+        match disk.mode{
+            DiskMode::Overlay => {
+                let xml = reg.render("volume", &json!({
+                    "name": volume,
+                    "capacity": capacity,
+                    "format": "qcow2",
+                    "backing": {"path": image_volume.get_path()?, "format": image_format},
+                }))?;
+                StorageVol::create_xml(&pool, &xml, 0)
+                    .with_context(|| format!("failed to create volume {} as overlay of image {}", volume, image))?;
+            },
+            DiskMode::Clone => {
+                let xml = reg.render("volume", &json!({
+                    "name": volume,
+                    "capacity": capacity,
+                    "format": "qcow2",
+                }))?;
+                let created = StorageVol::create_xml_from(&pool, &xml, &image_volume, 0)
+                    .with_context(|| format!("failed to create volume {} as clone of image {}", volume, image))?;
+                if created.get_info()?.capacity < capacity{
+                    created.resize(capacity, 0)?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn upload_volume(&self, storage: &StorageConfig, volume: &str, source: &str) -> anyhow::Result<()>{
+        let data = self.host().read_file(source)?;
+        let pool = self.storage_pool(storage)?;
+        // a running guest keeps the old volume open until the medium is swapped
+        if let Some(leftover) = VirtManager::lookup_volume(&pool, volume)?{
+            leftover.delete(0)?;
+        }
+        let xml = templates()?.render("volume", &json!({
+            "name": volume,
+            "capacity": data.len(),
+            "format": "raw",
+        }))?;
+        let created = StorageVol::create_xml(&pool, &xml, 0)?;
+        if !data.is_empty(){
+            self.upload(&created, &data).with_context(|| format!("failed to upload {} to volume {}", source, volume))?;
+        }
+        Ok(())
+    }
+
+    fn define_domain(&self, _name: &str, xml: &str) -> anyhow::Result<()>{
+        Domain::define_xml(&self.conn, xml)?;
+        Ok(())
+    }
+
+    fn create_domain(&self, _name: &str, xml: &str) -> anyhow::Result<()>{
+        Domain::create_xml(&self.conn, xml, 0)?;
+        Ok(())
+    }
+
+    fn start_domain(&self, name: &str) -> anyhow::Result<()>{
+        let domain = self.lookup_domain(name)?.ok_or(anyhow!("instance {} is not defined", name))?;
+        domain.create()?;
+        Ok(())
+    }
+
+    fn set_autostart(&self, kind: &'static str, name: &str, autostart: bool) -> anyhow::Result<()>{
+        match kind{
+            "network" => {
+                let network = self.lookup_network(name)?.ok_or(anyhow!("libvirt network {} not found", name))?;
+                network.set_autostart(autostart)?;
+            },
+            _ => {
+                let domain = self.lookup_domain(name)?.ok_or(anyhow!("instance {} is not defined", name))?;
+                domain.set_autostart(autostart)?;
+            },
+        }
+        Ok(())
+    }
+}
+
+fn templates() -> anyhow::Result<Handlebars<'static>>{
+    let mut reg = Handlebars::new();
+    reg.register_template_string("pool", POOL_DEV)?;
+    reg.register_template_string("volume", VOLUME_DEV)?;
+    Ok(reg)
+}
+
+// the template sees the instance runtime, its memory in KiB, the volumes and the typed default domain,
+// interfaces and cdrom are also rendered to xml for {{{ }}} inclusion
+fn domain_template(path: &str, name: &str, instance: &InstanceRuntime, storage: &StorageConfig, domain: &DomainDef) -> anyhow::Result<Element>{
+    let template = std::fs::read_to_string(path)?;
+    let interfaces: BTreeMap<&String, String> = instance.interfaces.iter().zip(&domain.interfaces)
+        .map(|((name, _), interface)| (name, Element::from(interface).to_string()))
+        .collect();
+    let cdrom = domain.disks.iter().find(|disk| disk.device == DiskDevice::Cdrom).map(|disk| Element::from(disk).to_string());
+    let mut reg = Handlebars::new();
+    // values are escaped for xml instead of html
+    reg.register_escape_fn(xml::escape);
+    let xml = reg.render_template(&template, &json!({
+        "name": name,
+        "domain_type": domain.domain_type,
+        "instance": instance,
+        "memory_kib": instance.memory.kib(),
+        "domain": domain,
+        "pool": storage.pool,
+        "disk": disk_volume(name),
+        "seed_iso": seed_iso_volume(name),
+        "serial_log": serial_log_path(name),
+        "interfaces": interfaces,
+        "cdrom": cdrom,
+    }))?;
+    Ok(Element::parse(&xml)?)
+}
+
+// path and content of the files packed into the seed iso
+pub fn seed_iso_files(name: &str, instance: &InstanceRuntime, runtime: &Runtime) -> anyhow::Result<Vec<(String, String)>>{
+    let cloud_init = CloudInit::build(name, instance, runtime)?;
+    let cloud_init_dir = cloud_init_dir(name);
+    Ok(vec![
+        (format!("{}/meta-data", cloud_init_dir), cloud_init.meta_data),
+        (format!("{}/user-data", cloud_init_dir), cloud_init.user_data),
+        (format!("{}/network-config", cloud_init_dir), cloud_init.network_config),
+    ])
+}
+
+pub fn seed_iso_command(name: &str, files: &[(String, String)]) -> (&'static str, Vec<String>){
+    let seed_iso = seed_iso_file(name);
+    let mut args = args(&[
+        "-output", &seed_iso,
+        "-quiet",
+        "-volid", "cidata",
+        "-input-charset", "utf-8",
+        "-joliet",
+        "-rock",
+    ]);
+    args.extend(files.iter().map(|(path, _)| path.clone()));
+    ("/usr/bin/genisoimage", args)
+}
+
+pub fn seed_iso_file(name: &str) -> String{
+    format!("{}/cidata.iso", cloud_init_dir(name))
+}
+
+pub const DOMAIN_TYPE: &str = "qemu";
+
+const IMAGE_DIR: &str = "/var/lib/libvirt/images";
+
+pub fn disk_volume(name: &str) -> String{
+    format!("{}.img", name)
+}
+
+pub fn seed_iso_volume(name: &str) -> String{
+    format!("{}-cidata.iso", name)
+}
+
+// only lives while the seed iso is built
+pub fn cloud_init_dir(name: &str) -> String{
+    format!("{}/virt-rs-{}-{}-cloud-init", std::env::temp_dir().display(), std::process::id(), name)
+}
+
+fn remove_tap(host: &dyn Host, name: &str) -> anyhow::Result<()>{
+    // libvirt normally removes the tap together with the domain, this catches leftovers
+    if !link_exists(host, name){
+        return Ok(());
+    }
+    host.run_command("ip", &args(&["link", "delete", name]))?;
+    Ok(())
+}
+
+// the kernel reports process start times in USER_HZ, which is 100 on all architectures libvirt runs on
+const USER_HZ: u64 = 100;
+const LOCAL_SYSTEM_URI: &str = "qemu:///system";
+
+// age of the qemu process, read from the pid file of the local system instance and /proc
+fn domain_uptime(domain_name: &str) -> Option<u64>{
+    let pid = std::fs::read_to_string(format!("/run/libvirt/qemu/{}.pid", domain_name)).ok()?;
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid.trim())).ok()?;
+    // the command name in parentheses may contain spaces, the fields after it don't
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let start_ticks: u64 = fields.get(19)?.parse().ok()?;
+    let boot_time: u64 = std::fs::read_to_string("/proc/stat").ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()?;
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).ok()?.as_secs();
+    now.checked_sub(boot_time + start_ticks / USER_HZ)
+}
+
+fn memory_used(domain: &Domain) -> Option<u64>{
+    let stats = domain.memory_stats(0).ok()?;
+    let stat = |tag| stats.iter().find(|stat| stat.tag == tag).map(|stat| stat.val);
+    match (stat(sys::VIR_DOMAIN_MEMORY_STAT_AVAILABLE), stat(sys::VIR_DOMAIN_MEMORY_STAT_UNUSED)){
+        (Some(available), Some(unused)) => available.checked_sub(unused),
+        _ => None,
+    }
+}
+
+// tap device of every interface in the live domain xml, keyed by mac
+fn interface_targets(xml: &str) -> anyhow::Result<BTreeMap<String, String>>{
+    let domain = Element::parse(xml)?;
+    let mut targets = BTreeMap::new();
+    let interfaces = domain.find("devices").into_iter().flat_map(|devices| devices.find_all("interface"));
+    for interface in interfaces{
+        let mac = interface.find("mac").and_then(|mac| mac.attribute("address"));
+        let tap = interface.find("target").and_then(|target| target.attribute("dev"));
+        if let (Some(mac), Some(tap)) = (mac, tap){
+            targets.insert(mac.to_lowercase(), tap.to_string());
+        }
+    }
+    Ok(targets)
+}
+
+// macs of the interfaces of a domain, lowercase like MacAddr displays them
+fn interface_macs(xml: &str) -> anyhow::Result<BTreeSet<String>>{
+    let domain = Element::parse(xml)?;
+    let interfaces = domain.find("devices").into_iter().flat_map(|devices| devices.find_all("interface"));
+    Ok(interfaces
+        .filter_map(|interface| interface.find("mac").and_then(|mac| mac.attribute("address")))
+        .map(|mac| mac.to_lowercase())
+        .collect())
+}
+
+// root ports without a device behind them, none on machines without pcie root ports
+fn free_root_ports(xml: &str) -> anyhow::Result<Option<usize>>{
+    let domain = Element::parse(xml)?;
+    let devices = match domain.find("devices"){
+        Some(devices) => devices,
+        None => return Ok(None),
+    };
+    let ports: Vec<u32> = devices.find_all("controller")
+        .filter(|controller| controller.attribute("type") == Some("pci") && controller.attribute("model") == Some("pcie-root-port"))
+        .filter_map(|controller| controller.attribute("index")?.parse().ok())
+        .collect();
+    if ports.is_empty(){
+        return Ok(None);
+    }
+    let buses: BTreeSet<u32> = devices.children.iter()
+        .filter_map(|device| device.find("address"))
+        .filter(|address| address.attribute("type") == Some("pci"))
+        .filter_map(|address| u32::from_str_radix(address.attribute("bus")?.trim_start_matches("0x"), 16).ok())
+        .collect();
+    Ok(Some(ports.iter().filter(|port| !buses.contains(port)).count()))
+}
+
+// the target format, the backing store of an overlay has a format element of its own
+fn volume_format(xml: &str) -> anyhow::Result<Option<String>>{
+    let volume = Element::parse(xml)?;
+    Ok(volume.find_path("target/format").and_then(|format| format.attribute("type")).map(|format| format.to_string()))
+}
+
+// returns false if the domain is still running after the timeout
+fn wait_for_shutdown(domain: &Domain, timeout: Duration) -> anyhow::Result<bool>{
+    let start = Instant::now();
+    while start.elapsed() < timeout{
+        if !domain.is_active()?{
+            return Ok(true);
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+    Ok(!domain.is_active()?)
+}
+
+// returns false if the live domain still has an interface with the mac after the timeout
+fn wait_for_detach(domain: &Domain, mac: MacAddr, timeout: Duration) -> anyhow::Result<bool>{
+    let start = Instant::now();
+    let mac = mac.to_string();
+    loop{
+        if !interface_macs(&domain.get_xml_desc(0)?)?.contains(&mac){
+            return Ok(true);
+        }
+        if start.elapsed() >= timeout{
+            return Ok(false);
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+}
+
+// devices change in the running domain and, if it is persistent, in its definition as well
+fn device_flags(domain: &Domain, persistent: bool) -> anyhow::Result<u32>{
+    let mut flags = 0;
+    if domain.is_active()?{
+        flags |= sys::VIR_DOMAIN_AFFECT_LIVE;
+    }
+    if persistent{
+        flags |= sys::VIR_DOMAIN_AFFECT_CONFIG;
+    }
+    Ok(flags)
+}
+
+fn dhcp_host_xml(host: &DhcpHost) -> String{
+    format!("<host mac='{}' ip='{}' name='{}'/>", host.mac, host.ip, host.name)
+}
+
+fn link_exists(host: &dyn Host, name: &str) -> bool{
+    host.path_exists(&format!("/sys/class/net/{}", name))
+}
+
+// bridges are tagged with the topology name, so teardown only removes what virt-rs created.
+// returns true if the bridge was created, false if the topology already has it, and fails
+// if the bridge belongs to something else.
+pub fn create_bridge(host: &dyn Host, bridge: &BridgeRuntime, topology: &str) -> anyhow::Result<bool>{
+    if link_exists(host, &bridge.name){
+        let owner = bridge_owner(host, bridge);
+        if owner != bridge_tag(topology){
+            let owner = if owner.is_empty(){ "no topology".to_string() } else { owner };
+            return Err(anyhow!("bridge {} already exists and belongs to {}", bridge.name, owner));
+        }
+        return Ok(false);
+    }
+    for (program, args) in bridge_commands(bridge, topology){
+        host.run_command(program, &args)?;
+    }
+    Ok(true)
+}
+
+pub fn bridge_commands(bridge: &BridgeRuntime, topology: &str) -> Vec<(&'static str, Vec<String>)>{
+    let tag = bridge_tag(topology);
+    let name = bridge.name.as_str();
+    let mut commands = match bridge.bridge_type{
+        BridgeType::Linux => vec![
+            ("ip", args(&["link", "add", "name", name, "type", "bridge"])),
+            ("ip", args(&["link", "set", "dev", name, "alias", &tag])),
+        ],
+        BridgeType::Ovs => vec![
+            ("ovs-vsctl", args(&[
+                "--may-exist", "add-br", name,
+                "--", "br-set-external-id", name, BRIDGE_TAG_KEY, &tag,
+            ])),
+        ],
+    };
+    commands.push(("ip", args(&["link", "set", "dev", name, "up"])));
+    commands
+}
+
+// returns true if the bridge was removed
+fn destroy_bridge(host: &dyn Host, bridge: &BridgeRuntime, topology: &str) -> anyhow::Result<bool>{
+    if !link_exists(host, &bridge.name){
+        return Ok(false);
+    }
+    if bridge_owner(host, bridge) != bridge_tag(topology){
+        return Ok(false);
+    }
+    match bridge.bridge_type{
+        BridgeType::Linux => {
+            host.run_command("ip", &args(&["link", "delete", &bridge.name, "type", "bridge"]))?;
+        },
+        BridgeType::Ovs => {
+            host.run_command("ovs-vsctl", &args(&["--if-exists", "del-br", &bridge.name]))?;
+        },
+    }
+    Ok(true)
+}
+
+const BRIDGE_TAG_KEY: &str = "virt-rs";
+
+fn bridge_tag(topology: &str) -> String{
+    format!("{}:{}", BRIDGE_TAG_KEY, topology)
+}
+
+// the tag of an existing bridge, empty if it has none
+fn bridge_owner(host: &dyn Host, bridge: &BridgeRuntime) -> String{
+    let owner = match bridge.bridge_type{
+        BridgeType::Linux => host.read_file(&format!("/sys/class/net/{}/ifalias", bridge.name))
+            .map(|alias| String::from_utf8_lossy(&alias).to_string())
+            .unwrap_or_default(),
+        // a linux bridge of the same name makes ovs-vsctl fail, it has no external id then
+        BridgeType::Ovs => host.run_command("ovs-vsctl", &args(&["br-get-external-id", &bridge.name, BRIDGE_TAG_KEY])).unwrap_or_default(),
+    };
+    // ovs-vsctl quotes values containing a colon
+    owner.trim().trim_matches('"').to_string()
+}
+
+fn args(args: &[&str]) -> Vec<String>{
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+fn state_name(state: sys::virDomainState) -> &'static str{
+    match state{
+        sys::VIR_DOMAIN_RUNNING => "running",
+        sys::VIR_DOMAIN_BLOCKED => "blocked",
+        sys::VIR_DOMAIN_PAUSED => "paused",
+        sys::VIR_DOMAIN_SHUTDOWN => "shutting down",
+        sys::VIR_DOMAIN_SHUTOFF => "shut off",
+        sys::VIR_DOMAIN_CRASHED => "crashed",
+        sys::VIR_DOMAIN_PMSUSPENDED => "suspended",
+        _ => "unknown",
+    }
+}
+
+// next to the qemu log of the domain, not in the pool where it would show up as a volume
+fn serial_log_path(name: &str) -> String{
+    format!("/var/log/libvirt/qemu/{}-serial.log", name)
+}
+
+const NETWORK_DEV: &str = r#"
+<network>
+  <name>{{ name }}</name>
+  {{#if forward}}
+  <forward mode='{{ forward }}'/>
+  {{/if}}
+  {{#if domain}}
+  <domain name='{{ domain }}' localOnly='yes'/>
+  {{/if}}
+  <ip address='{{ gateway }}' netmask='{{ netmask }}'>
+    {{#if (or dhcp_range hosts)}}
+    <dhcp>
+      {{#if dhcp_range}}
+      <range start='{{ dhcp_range.start }}' end='{{ dhcp_range.end }}'/>
+      {{/if}}
+      {{#each hosts as |host|}}
+      <host mac='{{ host.mac }}' ip='{{ host.ip }}' name='{{ host.name }}'/>
+      {{/each}}
+    </dhcp>
     {{/if}}
-    </interface>
-    {{/each}}
-    <console type="pty"/>
-    <channel type="unix">
-      <source mode="bind"/>
-      <target type="virtio" name="org.qemu.guest_agent.0"/>
-    </channel>
-    <input type="tablet" bus="usb"/>
-    <graphics type="vnc" port="-1" listen="0.0.0.0"/>
-    <video>
-      <model type="vga"/>
-    </video>
-    <memballoon model="virtio"/>
-    <rng model="virtio">
-      <backend model="random">/dev/urandom</backend>
-    </rng>
-    <disk type="file" device="cdrom">
-      <driver name="qemu" type="raw"/>
-      <source file="/var/lib/libvirt/images/cidata.iso"/>
-      <target dev="sda" bus="sata"/>
-      <readonly/>
-    </disk>
-    <serial type='file'>
-        <source path='/var/lib/libvirt/images/{{ @key }}.log'/>
-    <target port='0'/>
-  </serial>
-  </devices>
-</domain>
-{{/each}}"#;
\ No newline at end of file
+  </ip>
+</network>
+"#;
+
+const POOL_DEV: &str = r#"
+<pool type='dir'>
+  <name>{{ name }}</name>
+  <target>
+    <path>{{ path }}</path>
+  </target>
+</pool>
+"#;
+
+// capacity in bytes, backing turns the volume into an overlay
+const VOLUME_DEV: &str = r#"
+<volume>
+  <name>{{ name }}</name>
+  <capacity unit='bytes'>{{ capacity }}</capacity>
+  <target>
+    <format type='{{ format }}'/>
+  </target>
+  {{#if backing}}
+  <backingStore>
+    <path>{{ backing.path }}</path>
+    <format type='{{ backing.format }}'/>
+  </backingStore>
+  {{/if}}
+</volume>
+"#;
@@ -0,0 +1 @@
+pub mod xml;
\ No newline at end of file
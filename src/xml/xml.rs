@@ -0,0 +1,534 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+// just enough xml for the documents libvirt reads and writes: elements, attributes and text,
+// comments and declarations are skipped when parsing
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Element{
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<Element>,
+    pub text: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct XmlError{
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for XmlError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid xml at byte {}: {}", self.position, self.message)
+    }
+}
+
+impl std::error::Error for XmlError{}
+
+// small edit of a generated document, paths look like /domain/cpu/@mode or
+// /domain/devices/interface[2]/model and missing elements without a predicate are created
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum XmlPatch{
+    // sets an attribute or, without @, the text of an element
+    Set{
+        set: String,
+        value: String,
+    },
+    // adds the xml as last child of the element
+    Append{
+        append: String,
+        xml: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatchError{
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for PatchError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+impl std::error::Error for PatchError{}
+
+// a step of a patch path, element name and an optional [n] or [path='value'] predicate
+#[derive(Debug, Clone, PartialEq)]
+struct Step{
+    name: String,
+    predicate: Option<Predicate>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Predicate{
+    // 1-based like xpath
+    Index(usize),
+    // relative path of child elements, like @name, source/@file or target
+    Equals(String, String),
+}
+
+impl XmlPatch{
+    pub fn path(&self) -> &str{
+        match self{
+            XmlPatch::Set{set, ..} => set,
+            XmlPatch::Append{append, ..} => append,
+        }
+    }
+
+    // finds syntax errors before anything is deployed
+    pub fn check(&self) -> Result<(), PatchError>{
+        let (_, attribute) = parse_path(self.path())?;
+        if let XmlPatch::Append{xml, ..} = self{
+            if attribute.is_some(){
+                return Err(self.error("can't append to an attribute"));
+            }
+            Element::parse(xml).map_err(|e| self.error(&e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    pub fn apply(&self, root: &mut Element) -> Result<(), PatchError>{
+        self.check()?;
+        let (steps, attribute) = parse_path(self.path())?;
+        if steps[0].name != root.name || steps[0].predicate.is_some(){
+            return Err(self.error(&format!("the document root is {}", root.name)));
+        }
+        let mut element = root;
+        for step in &steps[1..]{
+            element = element.select_mut(step).ok_or(self.error(&format!("no element matches {}", step)))?;
+        }
+        match (self, attribute){
+            (XmlPatch::Set{value, ..}, Some(attribute)) => {
+                match element.attributes.iter_mut().find(|(name, _)| *name == attribute){
+                    Some((_, current)) => *current = value.clone(),
+                    None => element.attributes.push((attribute, value.clone())),
+                }
+            },
+            (XmlPatch::Set{value, ..}, None) => element.text = Some(value.clone()),
+            (XmlPatch::Append{xml, ..}, _) => {
+                let child = Element::parse(xml).map_err(|e| self.error(&e.to_string()))?;
+                element.children.push(child);
+            },
+        }
+        Ok(())
+    }
+
+    fn error(&self, message: &str) -> PatchError{
+        PatchError{
+            path: self.path().to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for XmlPatch{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            XmlPatch::Set{set, value} => write!(f, "set {} to {}", set, value),
+            XmlPatch::Append{append, ..} => write!(f, "append to {}", append),
+        }
+    }
+}
+
+impl fmt::Display for Step{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.predicate{
+            Some(Predicate::Index(index)) => write!(f, "{}[{}]", self.name, index),
+            Some(Predicate::Equals(path, value)) => write!(f, "{}[{}='{}']", self.name, path, value),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+// absolute path of element steps, optionally ending in @attribute
+fn parse_path(path: &str) -> Result<(Vec<Step>, Option<String>), PatchError>{
+    let error = |message: &str| PatchError{
+        path: path.to_string(),
+        message: message.to_string(),
+    };
+    let relative = path.strip_prefix('/').ok_or(error("must start with /"))?;
+    let mut steps = Vec::new();
+    let mut attribute = None;
+    let segments = split_steps(relative).ok_or(error("unbalanced brackets or quotes"))?;
+    for (index, segment) in segments.iter().enumerate(){
+        if let Some(name) = segment.strip_prefix('@'){
+            if index + 1 != segments.len() || index == 0{
+                return Err(error("an attribute can only be the last step"));
+            }
+            if name.is_empty(){
+                return Err(error("empty attribute name"));
+            }
+            attribute = Some(name.to_string());
+            continue;
+        }
+        let (name, predicate) = match segment.split_once('['){
+            Some((name, predicate)) => {
+                let predicate = predicate.strip_suffix(']').ok_or(error(&format!("unterminated predicate in {}", segment)))?;
+                (name, Some(parse_predicate(predicate).ok_or(error(&format!("predicate [{}] is neither [n] nor [path='value']", predicate)))?))
+            },
+            None => (*segment, None),
+        };
+        if name.is_empty(){
+            return Err(error("empty step"));
+        }
+        steps.push(Step{name: name.to_string(), predicate});
+    }
+    Ok((steps, attribute))
+}
+
+// splits at the slashes outside of predicates, values in predicates may be paths themselves
+fn split_steps(path: &str) -> Option<Vec<&str>>{
+    let mut steps = Vec::new();
+    let mut depth = 0;
+    let mut quote = None;
+    let mut start = 0;
+    for (index, c) in path.char_indices(){
+        match (quote, c){
+            (Some(open), c) if c == open => quote = None,
+            (Some(_), _) => {},
+            (None, '\'' | '"') if depth > 0 => quote = Some(c),
+            (None, '[') => depth += 1,
+            (None, ']') => depth = usize::checked_sub(depth, 1)?,
+            (None, '/') if depth == 0 => {
+                steps.push(&path[start..index]);
+                start = index + 1;
+            },
+            _ => {},
+        }
+    }
+    if depth > 0 || quote.is_some(){
+        return None;
+    }
+    steps.push(&path[start..]);
+    Some(steps)
+}
+
+fn parse_predicate(predicate: &str) -> Option<Predicate>{
+    if let Ok(index) = predicate.parse::<usize>(){
+        return (index > 0).then_some(Predicate::Index(index));
+    }
+    let (path, value) = predicate.split_once('=')?;
+    let value = value.strip_prefix('\'').and_then(|value| value.strip_suffix('\''))
+        .or(value.strip_prefix('"').and_then(|value| value.strip_suffix('"')))?;
+    // element names, only the last one may be an attribute
+    let names: Vec<&str> = path.split('/').collect();
+    let valid = names.iter().enumerate().all(|(index, name)| match name.strip_prefix('@'){
+        Some(attribute) => index + 1 == names.len() && !attribute.is_empty(),
+        None => !name.is_empty() && !name.contains(['[', ']', '\'', '"']),
+    });
+    valid.then(|| Predicate::Equals(path.to_string(), value.to_string()))
+}
+
+impl Element{
+    pub fn new(name: &str) -> Element{
+        Element{
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn attr(mut self, name: &str, value: impl ToString) -> Element{
+        self.attributes.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn child(mut self, child: Element) -> Element{
+        self.children.push(child);
+        self
+    }
+
+    pub fn children(mut self, children: impl IntoIterator<Item = Element>) -> Element{
+        self.children.extend(children);
+        self
+    }
+
+    pub fn text(mut self, text: impl ToString) -> Element{
+        self.text = Some(text.to_string());
+        self
+    }
+
+    pub fn attribute(&self, name: &str) -> Option<&str>{
+        self.attributes.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    // first direct child with the name
+    pub fn find(&self, name: &str) -> Option<&Element>{
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    pub fn find_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> + 'a{
+        self.children.iter().filter(move |child| child.name == name)
+    }
+
+    // follows a path of child names like devices/interface
+    pub fn find_path(&self, path: &str) -> Option<&Element>{
+        path.split('/').try_fold(self, |element, name| element.find(name))
+    }
+
+    // attribute or text at a relative path like source/@file
+    fn value(&self, path: &str) -> Option<&str>{
+        let (elements, attribute) = match path.rsplit_once('/'){
+            Some((elements, last)) if last.starts_with('@') => (Some(elements), Some(&last[1..])),
+            None if path.starts_with('@') => (None, Some(&path[1..])),
+            _ => (Some(path), None),
+        };
+        let element = match elements{
+            Some(elements) => self.find_path(elements)?,
+            None => self,
+        };
+        match attribute{
+            Some(attribute) => element.attribute(attribute),
+            None => element.text.as_deref(),
+        }
+    }
+
+    // the child a patch step points at, a plain step creates the child if there is none
+    fn select_mut(&mut self, step: &Step) -> Option<&mut Element>{
+        let mut matching = self.children.iter().enumerate().filter(|(_, child)| child.name == step.name);
+        let index = match &step.predicate{
+            None => matching.next().map(|(index, _)| index),
+            Some(Predicate::Index(n)) => matching.nth(n - 1).map(|(index, _)| index),
+            Some(Predicate::Equals(path, value)) => matching.find(|(_, child)| child.value(path) == Some(value)).map(|(index, _)| index),
+        };
+        match (index, &step.predicate){
+            (Some(index), _) => Some(&mut self.children[index]),
+            (None, None) => {
+                self.children.push(Element::new(&step.name));
+                self.children.last_mut()
+            },
+            (None, Some(_)) => None,
+        }
+    }
+
+    pub fn parse(xml: &str) -> Result<Element, XmlError>{
+        let mut parser = Parser{xml, position: 0};
+        parser.skip_misc()?;
+        let element = parser.element()?;
+        parser.skip_misc()?;
+        if parser.position < xml.len(){
+            return Err(parser.error("content after the root element"));
+        }
+        Ok(element)
+    }
+
+    fn write(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        let indent = "  ".repeat(depth);
+        write!(f, "{}<{}", indent, self.name)?;
+        for (name, value) in &self.attributes{
+            write!(f, " {}='{}'", name, escape(value))?;
+        }
+        match (&self.text, self.children.is_empty()){
+            (None, true) => writeln!(f, "/>"),
+            (Some(text), true) => writeln!(f, ">{}</{}>", escape(text), self.name),
+            (text, false) => {
+                writeln!(f, ">")?;
+                if let Some(text) = text{
+                    writeln!(f, "{}  {}", indent, escape(text))?;
+                }
+                for child in &self.children{
+                    child.write(f, depth + 1)?;
+                }
+                writeln!(f, "{}</{}>", indent, self.name)
+            },
+        }
+    }
+}
+
+impl fmt::Display for Element{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+// also used for values rendered into xml templates
+pub fn escape(value: &str) -> String{
+    value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('\'', "&apos;").replace('"', "&quot;")
+}
+
+// predefined entities and numeric character references, anything else is kept as it is
+fn unescape(value: &str) -> String{
+    let mut unescaped = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('&'){
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let reference = rest[1..].find(';').map(|end| &rest[1..end + 1]);
+        let decoded = reference.and_then(|reference| match reference{
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "apos" => Some('\''),
+            "quot" => Some('"'),
+            _ => match reference.strip_prefix("#x").or(reference.strip_prefix("#X")){
+                Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                None => reference.strip_prefix('#').and_then(|decimal| decimal.parse().ok()),
+            }.and_then(char::from_u32),
+        });
+        match (reference, decoded){
+            (Some(reference), Some(decoded)) => {
+                unescaped.push(decoded);
+                rest = &rest[reference.len() + 2..];
+            },
+            _ => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            },
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+struct Parser<'a>{
+    xml: &'a str,
+    position: usize,
+}
+
+impl<'a> Parser<'a>{
+    fn error(&self, message: &str) -> XmlError{
+        XmlError{
+            position: self.position,
+            message: message.to_string(),
+        }
+    }
+
+    fn rest(&self) -> &'a str{
+        &self.xml[self.position..]
+    }
+
+    fn skip_whitespace(&mut self){
+        let rest = self.rest();
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    fn skip_past(&mut self, end: &str) -> Result<(), XmlError>{
+        match self.rest().find(end){
+            Some(offset) => {
+                self.position += offset + end.len();
+                Ok(())
+            },
+            None => Err(self.error(&format!("missing {}", end))),
+        }
+    }
+
+    // a doctype ends at the first > outside its internal [subset] and quotes
+    fn skip_doctype(&mut self) -> Result<(), XmlError>{
+        let mut depth = 0;
+        let mut quote = None;
+        while let Some(c) = self.rest().chars().next(){
+            if quote.is_none() && depth > 0 && self.rest().starts_with("<!--"){
+                self.skip_past("-->")?;
+                continue;
+            }
+            self.position += c.len_utf8();
+            match (quote, c){
+                (Some(open), c) if c == open => quote = None,
+                (Some(_), _) => {},
+                (None, '\'' | '"') => quote = Some(c),
+                (None, '[') => depth += 1,
+                (None, ']') if depth > 0 => depth -= 1,
+                (None, '>') if depth == 0 => return Ok(()),
+                _ => {},
+            }
+        }
+        Err(self.error("missing >"))
+    }
+
+    // whitespace, comments, declarations and doctypes
+    fn skip_misc(&mut self) -> Result<(), XmlError>{
+        loop{
+            self.skip_whitespace();
+            if self.rest().starts_with("<!--"){
+                self.skip_past("-->")?;
+            } else if self.rest().starts_with("<?"){
+                self.skip_past("?>")?;
+            } else if self.rest().starts_with("<!"){
+                self.skip_doctype()?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<&'a str, XmlError>{
+        let rest = self.rest();
+        let end = rest.find(|c: char| c.is_whitespace() || c == '/' || c == '>' || c == '=').unwrap_or(rest.len());
+        if end == 0{
+            return Err(self.error("expected a name"));
+        }
+        self.position += end;
+        Ok(&rest[..end])
+    }
+
+    fn element(&mut self) -> Result<Element, XmlError>{
+        if !self.rest().starts_with('<'){
+            return Err(self.error("expected an element"));
+        }
+        self.position += 1;
+        let mut element = Element::new(self.name()?);
+        loop{
+            self.skip_whitespace();
+            let rest = self.rest();
+            if rest.starts_with("/>"){
+                self.position += 2;
+                return Ok(element);
+            }
+            if rest.starts_with('>'){
+                self.position += 1;
+                break;
+            }
+            let name = self.name()?;
+            self.skip_whitespace();
+            if !self.rest().starts_with('='){
+                return Err(self.error("expected '=' after attribute name"));
+            }
+            self.position += 1;
+            self.skip_whitespace();
+            let quote = self.rest().chars().next().filter(|c| *c == '\'' || *c == '"').ok_or(self.error("expected a quoted attribute value"))?;
+            self.position += 1;
+            let end = self.rest().find(quote).ok_or(self.error("unterminated attribute value"))?;
+            let value = unescape(&self.rest()[..end]);
+            self.position += end + 1;
+            element.attributes.push((name.to_string(), value));
+        }
+        let mut text = String::new();
+        loop{
+            let rest = self.rest();
+            if rest.starts_with("</"){
+                self.position += 2;
+                let name = self.name()?;
+                if name != element.name{
+                    return Err(self.error(&format!("expected </{}>, found </{}>", element.name, name)));
+                }
+                self.skip_whitespace();
+                self.skip_past(">")?;
+                break;
+            } else if rest.starts_with("<!--"){
+                self.skip_past("-->")?;
+            } else if rest.starts_with("<![CDATA["){
+                self.position += "<![CDATA[".len();
+                let end = self.rest().find("]]>").ok_or(self.error("unterminated cdata"))?;
+                text.push_str(&self.rest()[..end]);
+                self.position += end + 3;
+            } else if rest.starts_with('<'){
+                element.children.push(self.element()?);
+            } else if rest.is_empty(){
+                return Err(self.error(&format!("missing </{}>", element.name)));
+            } else {
+                let end = rest.find('<').unwrap_or(rest.len());
+                text.push_str(&unescape(&rest[..end]));
+                self.position += end;
+            }
+        }
+        let text = text.trim();
+        if !text.is_empty(){
+            element.text = Some(text.to_string());
+        }
+        Ok(element)
+    }
+}
@@ -0,0 +1,39 @@
+// cloud-init seed iso contents of an instance
+mod common;
+
+use common::example;
+use virt_rs::cloud_init::cloud_init::CloudInit;
+use virt_rs::config::config::UserConfig;
+use virt_rs::runtime::runtime::Runtime;
+
+#[test]
+fn ssh_keys_reach_the_user_data_unescaped(){
+    let key = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE9x+/ab== o'brien@lab `x` <y>";
+    let key_path = std::env::temp_dir().join(format!("virt-rs-{}-id.pub", std::process::id()));
+    std::fs::write(&key_path, format!("{}\n", key)).unwrap();
+    let mut config = example();
+    config.user_config = Some(UserConfig{
+        user_name: "ops".to_string(),
+        key_path: key_path.to_string_lossy().to_string(),
+    });
+    let runtime = Runtime::build(&config).unwrap();
+    let cloud_init = CloudInit::build("vm1", &runtime.instances["vm1"], &runtime);
+    std::fs::remove_file(&key_path).unwrap();
+    let cloud_init = cloud_init.unwrap();
+    assert!(cloud_init.user_data.starts_with("#cloud-config\n"));
+    let user_data: serde_yaml::Value = serde_yaml::from_str(&cloud_init.user_data).unwrap();
+    let users = user_data["users"].as_sequence().unwrap();
+    let ops = users.iter().find(|user| user["name"] == "ops").unwrap();
+    assert_eq!(ops["ssh-authorized-keys"][0].as_str(), Some(key));
+}
+
+#[test]
+fn meta_data_names_the_instance(){
+    let runtime = Runtime::build(&example()).unwrap();
+    let cloud_init = CloudInit::build("vm2", &runtime.instances["vm2"], &runtime).unwrap();
+    let meta_data: serde_yaml::Value = serde_yaml::from_str(&cloud_init.meta_data).unwrap();
+    assert_eq!(meta_data["instance-id"].as_str(), Some(runtime.instances["vm2"].uuid.as_str()));
+    assert_eq!(meta_data["local-hostname"].as_str(), Some("vm2"));
+    let user_data: serde_yaml::Value = serde_yaml::from_str(&cloud_init.user_data).unwrap();
+    assert!(user_data.get("users").is_none());
+}
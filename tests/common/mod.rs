@@ -0,0 +1,9 @@
+// fixtures shared by the integration tests
+use virt_rs::config::config::Config;
+
+// the example topology, without a user config so no ssh key has to exist
+pub fn example() -> Config{
+    let mut config = Config::example();
+    config.user_config = None;
+    config
+}
@@ -0,0 +1,43 @@
+// parsing the config and the state it leaves behind
+use virt_rs::instance::instance::{InstanceConfig, InstanceRuntime, Memory};
+use virt_rs::network::network::{NetworkMode, NetworkTypeConfig};
+
+#[test]
+fn memory_needs_a_unit(){
+    let instance: InstanceConfig = serde_yaml::from_str("{vcpu: 1, memory: 512MiB, image: img}").unwrap();
+    assert_eq!(instance.memory, Memory::from_mib(512));
+    assert!(serde_yaml::from_str::<InstanceConfig>("{vcpu: 1, memory: 4, image: img}").is_err());
+    assert_eq!(serde_yaml::to_string(&Memory::from_gib(4)).unwrap().trim(), "4GiB");
+    assert_eq!(Memory::from_mib(1536).to_string(), "1536MiB");
+    for invalid in ["1024", "4 GB", "GiB", "-1GiB"]{
+        assert!(invalid.parse::<Memory>().is_err(), "{} was accepted", invalid);
+    }
+}
+
+#[test]
+fn state_from_before_memory_units_is_read_as_gib(){
+    let state = "{uuid: 0, vcpu: 1, memory: 4, image: img, persistent: true, autostart: false, interfaces: {}, route_tables: {}}";
+    let instance: InstanceRuntime = serde_yaml::from_str(state).unwrap();
+    assert_eq!(instance.memory, Memory::from_gib(4));
+    let instance: InstanceRuntime = serde_yaml::from_str(&state.replace("memory: 4", "memory: 512MiB")).unwrap();
+    assert_eq!(instance.memory, Memory::from_mib(512));
+}
+
+#[test]
+fn network_types_follow_from_their_keys_and_reject_unknown_ones(){
+    for yaml in ["{name: default}", "{mode: nat, subnet: 192.168.1.0/24}", "{subnet: 10.0.0.0/24, bridge_type: ovs}", "{subnet6: fd00::/64}"]{
+        let network_type: NetworkTypeConfig = serde_yaml::from_str(yaml).unwrap();
+        // written back without a tag
+        assert_eq!(serde_yaml::from_str::<NetworkTypeConfig>(&serde_yaml::to_string(&network_type).unwrap()).unwrap(), network_type);
+    }
+    assert!(matches!(serde_yaml::from_str("{mode: route, subnet: 10.0.0.0/24}").unwrap(), NetworkTypeConfig::Libvirt{mode: NetworkMode::Route, ..}));
+    for (yaml, error) in [
+        ("{mode: bridge, subnet: 10.0.0.0/24}", "unknown variant `bridge`"),
+        ("{mode: nat, subnet: 10.0.0.0/24, dhcp_rang: {start: 10.0.0.10, end: 10.0.0.20}}", "unknown field `dhcp_rang`"),
+        ("{subnet: 10.0.0.0/24, gatway: 10.0.0.1}", "unknown field `gatway`"),
+        ("{name: default, subnet: 10.0.0.0/24}", "unknown field `subnet`"),
+    ]{
+        let e = serde_yaml::from_str::<NetworkTypeConfig>(yaml).unwrap_err().to_string();
+        assert!(e.contains(error), "{}: {}", yaml, e);
+    }
+}
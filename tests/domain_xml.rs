@@ -0,0 +1,282 @@
+// the domain xml is built from typed structs, these parse it back and check what libvirt gets
+mod common;
+
+use common::example;
+use virt_rs::config::config::Config;
+use virt_rs::domain::domain::HOTPLUG_PORTS;
+use virt_rs::instance::instance::{CpuConfig, CpuMode, CpuTopology, GraphicsType, Memory, NumaCell};
+use virt_rs::runtime::runtime::Runtime;
+use virt_rs::virt_manager::virt_manager::{VirtManager, DOMAIN_TYPE};
+use virt_rs::xml::xml::{Element, XmlPatch};
+
+fn domain(config: &Config, name: &str) -> Element{
+    let runtime = Runtime::build(config).unwrap();
+    let xml = VirtManager::domain_xml(name, &runtime.instances[name], &runtime.storage, DOMAIN_TYPE).unwrap();
+    Element::parse(&xml).unwrap()
+}
+
+fn devices<'a>(domain: &'a Element, name: &'a str) -> Vec<&'a Element>{
+    domain.find("devices").unwrap().find_all(name).collect()
+}
+
+fn controllers(domain: &Element, model: &str) -> usize{
+    devices(domain, "controller").iter().filter(|controller| controller.attribute("model") == Some(model)).count()
+}
+
+fn cdrom(domain: &Element) -> &Element{
+    devices(domain, "disk").into_iter().find(|disk| disk.attribute("device") == Some("cdrom")).unwrap()
+}
+
+#[test]
+fn domain_carries_identity_and_size_of_the_instance(){
+    let config = example();
+    let runtime = Runtime::build(&config).unwrap();
+    for (name, instance) in &runtime.instances{
+        let domain = domain(&config, name);
+        assert_eq!(domain.attribute("type"), Some(DOMAIN_TYPE));
+        assert_eq!(domain.find("name").unwrap().text.as_deref(), Some(name.as_str()));
+        assert_eq!(domain.find("uuid").unwrap().text, Some(instance.uuid.clone()));
+        assert_eq!(domain.find("vcpu").unwrap().text, Some(instance.vcpu.to_string()));
+        let memory = domain.find("memory").unwrap();
+        assert_eq!(memory.attribute("unit"), Some("KiB"));
+        assert_eq!(memory.text, Some(instance.memory.kib().to_string()));
+    }
+}
+
+#[test]
+fn root_ports_follow_the_number_of_interfaces(){
+    let config = example();
+    let runtime = Runtime::build(&config).unwrap();
+    for (name, instance) in &runtime.instances{
+        let domain = domain(&config, name);
+        assert_eq!(controllers(&domain, "pcie-root"), 1);
+        // the disk, usb, serial channel, balloon and rng take a port each next to the nics
+        assert_eq!(controllers(&domain, "pcie-root-port"), 1 + 4 + instance.interfaces.len() + HOTPLUG_PORTS, "root ports of {}", name);
+    }
+}
+
+#[test]
+fn every_interface_is_attached_with_its_mac(){
+    let config = example();
+    let runtime = Runtime::build(&config).unwrap();
+    for (name, instance) in &runtime.instances{
+        let domain = domain(&config, name);
+        let interfaces = devices(&domain, "interface");
+        assert_eq!(interfaces.len(), instance.interfaces.len());
+        for (interface_name, interface) in &instance.interfaces{
+            let element = interfaces.iter().find(|element| element.find("mac").unwrap().attribute("address") == Some(&interface.mac.to_string()))
+                .unwrap_or_else(|| panic!("interface {} missing in domain {}", interface_name, name));
+            let source = element.find("source").unwrap();
+            match (&interface.managed, &interface.bridge){
+                (Some(network), _) => {
+                    assert_eq!(element.attribute("type"), Some("network"));
+                    assert_eq!(source.attribute("network"), Some(network.as_str()));
+                },
+                (None, Some(bridge)) => {
+                    assert_eq!(element.attribute("type"), Some("bridge"));
+                    assert_eq!(source.attribute("bridge"), Some(bridge.name.as_str()));
+                    assert_eq!(element.find("target").unwrap().attribute("dev"), Some(interface_name.as_str()));
+                    assert_eq!(element.find("mtu").unwrap().attribute("size"), Some(interface.mtu.to_string().as_str()));
+                },
+                (None, None) => unreachable!(),
+            }
+        }
+    }
+}
+
+#[test]
+fn disk_and_seed_iso_are_volumes_of_the_pool(){
+    let config = example();
+    let domain = domain(&config, "vm1");
+    let disks = devices(&domain, "disk");
+    assert_eq!(disks.len(), 2);
+    let disk = disks[0].find("source").unwrap();
+    assert_eq!(disk.attribute("pool"), Some(config.storage.pool.as_str()));
+    assert_eq!(disk.attribute("volume"), Some("vm1.img"));
+    let cdrom = cdrom(&domain);
+    assert_eq!(cdrom.find("source").unwrap().attribute("volume"), Some("vm1-cidata.iso"));
+    assert_eq!(cdrom.find("target").unwrap().attribute("bus"), Some("sata"));
+    assert!(cdrom.find("readonly").is_some());
+}
+
+#[test]
+fn pc_machines_get_no_root_ports_and_an_ide_cdrom(){
+    let mut config = example();
+    config.instances.get_mut("vm1").unwrap().domain.machine = "pc".to_string();
+    let domain = domain(&config, "vm1");
+    assert_eq!(domain.find_path("os/type").unwrap().attribute("machine"), Some("pc"));
+    assert_eq!(controllers(&domain, "pcie-root"), 0);
+    assert_eq!(controllers(&domain, "pcie-root-port"), 0);
+    let target = cdrom(&domain).find("target").unwrap();
+    assert_eq!(target.attribute("bus"), Some("ide"));
+    assert_eq!(target.attribute("dev"), Some("hda"));
+}
+
+#[test]
+fn other_architectures_get_a_scsi_cdrom_and_no_x86_devices(){
+    let mut config = example();
+    let instance = config.instances.get_mut("vm1").unwrap();
+    instance.domain.arch = "aarch64".to_string();
+    instance.domain.machine = "virt".to_string();
+    let domain = domain(&config, "vm1");
+    assert_eq!(domain.find_path("devices/emulator").unwrap().text.as_deref(), Some("/usr/bin/qemu-system-aarch64"));
+    assert!(domain.find_path("features/apic").is_none());
+    assert!(domain.find_path("clock/timer").is_none());
+    assert_eq!(cdrom(&domain).find("target").unwrap().attribute("bus"), Some("scsi"));
+    assert_eq!(controllers(&domain, "virtio-scsi"), 1);
+    assert_eq!(domain.find_path("devices/video/model").unwrap().attribute("type"), Some("virtio"));
+}
+
+#[test]
+fn graphics_can_be_changed_or_left_out(){
+    let mut config = example();
+    config.instances.get_mut("vm1").unwrap().domain.graphics = GraphicsType::None;
+    let vm2 = config.instances.get_mut("vm2").unwrap();
+    vm2.domain.graphics = GraphicsType::Spice;
+    vm2.domain.listen = "127.0.0.1".parse().unwrap();
+    let vm1 = domain(&config, "vm1");
+    assert!(devices(&vm1, "graphics").is_empty());
+    assert!(devices(&vm1, "video").is_empty());
+    let vm2 = domain(&config, "vm2");
+    let graphics = vm2.find_path("devices/graphics").unwrap();
+    assert_eq!(graphics.attribute("type"), Some("spice"));
+    assert_eq!(graphics.attribute("listen"), Some("127.0.0.1"));
+}
+
+#[test]
+fn patches_of_the_topology_come_before_those_of_the_instance(){
+    let mut config = example();
+    config.domain.patches = vec![
+        XmlPatch::Set{set: "/domain/cpu/@mode".to_string(), value: "host-model".to_string()},
+        XmlPatch::Append{append: "/domain/devices".to_string(), xml: "<watchdog model='i6300esb'/>".to_string()},
+    ];
+    config.instances.get_mut("vm1").unwrap().domain.patches = vec![
+        XmlPatch::Set{set: "/domain/cpu/@mode".to_string(), value: "host-passthrough".to_string()},
+        XmlPatch::Set{set: "/domain/devices/interface[1]/model/@type".to_string(), value: "e1000e".to_string()},
+    ];
+    config.validate().unwrap();
+    let vm1 = domain(&config, "vm1");
+    assert_eq!(vm1.find("cpu").unwrap().attribute("mode"), Some("host-passthrough"));
+    assert_eq!(vm1.find_path("devices/interface/model").unwrap().attribute("type"), Some("e1000e"));
+    assert_eq!(vm1.find_path("devices/watchdog").unwrap().attribute("model"), Some("i6300esb"));
+    let vm2 = domain(&config, "vm2");
+    assert_eq!(vm2.find("cpu").unwrap().attribute("mode"), Some("host-model"));
+}
+
+#[test]
+fn patches_with_a_predicate_do_not_create_elements(){
+    let mut config = example();
+    config.instances.get_mut("vm1").unwrap().domain.patches = vec![
+        XmlPatch::Set{set: "/domain/devices/interface[9]/mtu/@size".to_string(), value: "9000".to_string()},
+    ];
+    let runtime = Runtime::build(&config).unwrap();
+    assert!(VirtManager::domain_xml("vm1", &runtime.instances["vm1"], &runtime.storage, DOMAIN_TYPE).is_err());
+}
+
+#[test]
+fn templates_replace_the_built_in_domain(){
+    let template = std::env::temp_dir().join(format!("virt-rs-{}-domain.hbs", std::process::id()));
+    std::fs::write(&template, r#"<domain type='{{ domain_type }}'>
+  <name>{{ name }}</name>
+  <uuid>{{ instance.uuid }}</uuid>
+  <memory unit='KiB'>{{ memory_kib }}</memory>
+  <vcpu>{{ instance.vcpu }}</vcpu>
+  <description>{{ instance.image }}</description>
+  <cpu mode='host-passthrough'/>
+  <devices>
+    <disk type='volume' device='disk'>
+      <source pool='{{ pool }}' volume='{{ disk }}'/>
+      <target dev='vda' bus='virtio'/>
+    </disk>
+    {{{ cdrom }}}
+    {{#each interfaces}}{{{ this }}}{{/each}}
+  </devices>
+</domain>
+"#).unwrap();
+    let mut config = example();
+    config.domain.template = Some(template.to_string_lossy().to_string());
+    config.instances.get_mut("vm2").unwrap().image = "/images/vm's <a&b>=2.img".to_string();
+    config.instances.get_mut("vm2").unwrap().domain.patches = vec![
+        XmlPatch::Set{set: "/domain/vcpu".to_string(), value: "4".to_string()},
+    ];
+    let runtime = Runtime::build(&config).unwrap();
+    let vm2 = domain(&config, "vm2");
+    std::fs::remove_file(&template).unwrap();
+    assert_eq!(vm2.find("uuid").unwrap().text, Some(runtime.instances["vm2"].uuid.clone()));
+    assert_eq!(vm2.find("cpu").unwrap().attribute("mode"), Some("host-passthrough"));
+    assert_eq!(vm2.find("memory").unwrap().text, Some(runtime.instances["vm2"].memory.kib().to_string()));
+    assert_eq!(vm2.find("vcpu").unwrap().text.as_deref(), Some("4"));
+    // values are escaped for xml, not html
+    assert_eq!(vm2.find("description").unwrap().text.as_deref(), Some("/images/vm's <a&b>=2.img"));
+    assert_eq!(devices(&vm2, "interface").len(), runtime.instances["vm2"].interfaces.len());
+    assert_eq!(cdrom(&vm2).find("source").unwrap().attribute("volume"), Some("vm2-cidata.iso"));
+    assert_eq!(controllers(&vm2, "pcie-root-port"), 0);
+}
+
+#[test]
+fn template_paths_are_relative_to_the_config(){
+    let mut config = example();
+    config.domain.template = Some("templates/domain.hbs".to_string());
+    config.instances.get_mut("vm2").unwrap().domain.template = Some("/srv/vm2.hbs".to_string());
+    config.resolve_templates("/etc/lab/config.yaml");
+    assert_eq!(config.domain.template.as_deref(), Some("/etc/lab/templates/domain.hbs"));
+    assert_eq!(config.instances["vm2"].domain.template.as_deref(), Some("/srv/vm2.hbs"));
+    assert_eq!(config.instances["vm1"].domain.template, None);
+}
+
+fn numa_instance() -> Config{
+    let mut config = example();
+    let vm1 = config.instances.get_mut("vm1").unwrap();
+    vm1.vcpu = 4;
+    vm1.memory = Memory::from_gib(4);
+    vm1.hugepages = Some(Memory::from_mib(2));
+    vm1.cpu = CpuConfig{
+        mode: Some(CpuMode::HostPassthrough),
+        model: None,
+        topology: Some(CpuTopology{sockets: 2, cores: 2, threads: 1}),
+        pinning: [(0, "2".to_string()), (1, "3".to_string()), (2, "4-5".to_string()), (3, "4-7,^6".to_string())].into(),
+        numa: vec![
+            NumaCell{cpus: "0-1".to_string(), memory: Memory::from_gib(2)},
+            NumaCell{cpus: "2-3".to_string(), memory: Memory::from_mib(2048)},
+        ],
+    };
+    config
+}
+
+#[test]
+fn cpu_topology_pinning_numa_and_hugepages_are_rendered(){
+    let config = numa_instance();
+    config.validate().unwrap();
+    let vm1 = domain(&config, "vm1");
+    let cpu = vm1.find("cpu").unwrap();
+    assert_eq!(cpu.attribute("mode"), Some("host-passthrough"));
+    let topology = cpu.find("topology").unwrap();
+    assert_eq!((topology.attribute("sockets"), topology.attribute("cores"), topology.attribute("threads")), (Some("2"), Some("2"), Some("1")));
+    let cells: Vec<(&str, &str)> = cpu.find("numa").unwrap().find_all("cell")
+        .map(|cell| (cell.attribute("cpus").unwrap(), cell.attribute("memory").unwrap())).collect();
+    assert_eq!(cells, [("0-1", "2097152"), ("2-3", "2097152")]);
+    let pins: Vec<(&str, &str)> = vm1.find("cputune").unwrap().find_all("vcpupin")
+        .map(|pin| (pin.attribute("vcpu").unwrap(), pin.attribute("cpuset").unwrap())).collect();
+    assert_eq!(pins, [("0", "2"), ("1", "3"), ("2", "4-5"), ("3", "4-7,^6")]);
+    assert_eq!(vm1.find_path("memoryBacking/hugepages/page").unwrap().attribute("size"), Some("2048"));
+    assert_eq!(vm1.find("memory").unwrap().text.as_deref(), Some("4194304"));
+    // the other instance keeps the hypervisor defaults
+    let vm2 = domain(&config, "vm2");
+    assert!(vm2.find("cpu").is_none() && vm2.find("cputune").is_none() && vm2.find("memoryBacking").is_none());
+}
+
+#[test]
+fn a_model_alone_selects_a_custom_cpu(){
+    let mut config = example();
+    config.instances.get_mut("vm1").unwrap().cpu.model = Some("Skylake-Server".to_string());
+    config.validate().unwrap();
+    let cpu = domain(&config, "vm1").find("cpu").cloned().unwrap();
+    assert_eq!(cpu.attribute("mode"), Some("custom"));
+    assert_eq!(cpu.find("model").unwrap().text.as_deref(), Some("Skylake-Server"));
+}
+
+#[test]
+fn rendered_xml_parses_back_to_the_same_tree(){
+    let domain = domain(&example(), "vm1");
+    assert_eq!(Element::parse(&domain.to_string()).unwrap(), domain);
+}
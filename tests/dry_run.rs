@@ -0,0 +1,114 @@
+// the dry run backend records what up would do on the host, without a hypervisor
+mod common;
+
+use common::example;
+use virt_rs::backend::backend::{Backend, DryRun, Operation};
+use virt_rs::config::config::{Config, UserConfig};
+use virt_rs::network::network::NetworkTypeRuntime;
+use virt_rs::runtime::runtime::Runtime;
+use virt_rs::state::state::Resources;
+
+fn runtime(config: &Config) -> Runtime{
+    Runtime::build(config).unwrap()
+}
+
+fn up(runtime: &Runtime) -> (Vec<Operation>, Resources){
+    let dry_run = DryRun::new();
+    let mut resources = Resources::default();
+    let results = dry_run.create_topology(runtime, &mut resources).unwrap();
+    for (name, result) in &results{
+        assert!(result.is_ok(), "instance {} failed: {:?}", name, result);
+    }
+    (dry_run.operations(), resources)
+}
+
+fn position(operations: &[Operation], matches: impl Fn(&Operation) -> bool) -> usize{
+    operations.iter().position(matches).unwrap()
+}
+
+#[test]
+fn bridges_are_created_before_any_instance(){
+    let runtime = runtime(&example());
+    let (operations, resources) = up(&runtime);
+    let first_disk = position(&operations, |operation| matches!(operation, Operation::CreateDisk{..}));
+    for (name, network) in &runtime.networks{
+        let bridge = match &network.network_type{
+            NetworkTypeRuntime::Unmanaged{bridge, ..} => bridge,
+            _ => continue,
+        };
+        let add = position(&operations, |operation| matches!(operation,
+            Operation::Command{program, args} if program == "ip" && args[..3] == ["link", "add", "name"] && args[3] == bridge.name));
+        assert!(add < first_disk, "bridge of network {} is created after the instances", name);
+        assert_eq!(resources.bridges[name], *bridge);
+    }
+}
+
+#[test]
+fn every_instance_gets_a_disk_a_seed_iso_and_a_started_domain(){
+    let runtime = runtime(&example());
+    let (operations, resources) = up(&runtime);
+    for (name, instance) in &runtime.instances{
+        let disk = position(&operations, |operation| matches!(operation,
+            Operation::CreateDisk{volume, image, ..} if volume == &format!("{}.img", name) && image == &instance.image));
+        let seed_iso = position(&operations, |operation| matches!(operation,
+            Operation::UploadVolume{volume, ..} if volume == &format!("{}-cidata.iso", name)));
+        let define = position(&operations, |operation| matches!(operation,
+            Operation::DefineDomain{name: domain, xml} if domain == name && xml.contains(&instance.uuid)));
+        let start = position(&operations, |operation| matches!(operation,
+            Operation::StartDomain{name: domain} if domain == name));
+        assert!(disk < seed_iso && seed_iso < define && define < start, "operations of instance {} are out of order", name);
+        assert_eq!(resources.instances[name].domain, *name);
+    }
+}
+
+#[test]
+fn transient_instances_are_created_instead_of_defined(){
+    let mut config = example();
+    config.instances.get_mut("vm1").unwrap().persistent = false;
+    let (operations, _) = up(&runtime(&config));
+    assert!(operations.iter().any(|operation| matches!(operation, Operation::CreateDomain{name, ..} if name == "vm1")));
+    assert!(!operations.iter().any(|operation| matches!(operation,
+        Operation::DefineDomain{name, ..} | Operation::StartDomain{name} | Operation::Autostart{name, ..} if name == "vm1")));
+}
+
+#[test]
+fn seed_iso_carries_the_network_config_of_the_instance(){
+    let runtime = runtime(&example());
+    let (operations, _) = up(&runtime);
+    for (name, instance) in &runtime.instances{
+        let network_config = operations.iter().find_map(|operation| match operation{
+            Operation::WriteFile{path, content} if path.contains(&format!("-{}-cloud-init/", name)) && path.ends_with("/network-config") => Some(content),
+            _ => None,
+        }).unwrap();
+        for interface in instance.interfaces.values(){
+            assert!(network_config.contains(&interface.mac.to_string()), "network-config of {} misses {}", name, interface.mac);
+        }
+        let genisoimage = position(&operations, |operation| matches!(operation,
+            Operation::Command{program, args} if program.ends_with("genisoimage") && args.iter().any(|arg| arg.ends_with("/network-config") && arg.contains(&format!("-{}-cloud-init/", name)))));
+        let upload = position(&operations, |operation| matches!(operation,
+            Operation::UploadVolume{volume, ..} if volume == &format!("{}-cidata.iso", name)));
+        // the cloud-init files are only needed until the iso is uploaded
+        let remove = position(&operations, |operation| matches!(operation,
+            Operation::RemovePath{path} if path.ends_with(&format!("-{}-cloud-init", name))));
+        assert!(genisoimage < upload && upload < remove);
+    }
+}
+
+#[test]
+fn failing_instances_do_not_stop_the_others(){
+    let mut config = example();
+    config.user_config = Some(UserConfig{
+        user_name: "ubuntu".to_string(),
+        key_path: "/nonexistent/id_rsa.pub".to_string(),
+    });
+    let runtime = runtime(&config);
+    let dry_run = DryRun::new();
+    let mut resources = Resources::default();
+    let results = dry_run.create_topology(&runtime, &mut resources).unwrap();
+    assert_eq!(results.len(), runtime.instances.len());
+    assert!(results.values().all(|result| result.is_err()));
+    // the disks were already created when reading the key failed, teardown has to know about them
+    let disks = dry_run.operations().iter().filter(|operation| matches!(operation, Operation::CreateDisk{..})).count();
+    assert_eq!(disks, runtime.instances.len());
+    assert_eq!(resources.instances.len(), runtime.instances.len());
+}
@@ -0,0 +1,175 @@
+// planning against the deployed runtime and what is saved after applying it
+mod common;
+
+use common::example;
+use virt_rs::config::config::Config;
+use virt_rs::domain::domain::HOTPLUG_PORTS;
+use virt_rs::instance::instance::Memory;
+use virt_rs::interface::interface::InterfaceConfig;
+use virt_rs::plan::plan::{Action, Plan};
+use virt_rs::runtime::runtime::Runtime;
+
+fn plan(deployed: &Config, config: &Config) -> Plan{
+    let deployed = Runtime::build(deployed).unwrap();
+    let runtime = Runtime::rebuild(config, &deployed).unwrap();
+    Plan::new(&runtime, Some(&deployed))
+}
+
+#[test]
+fn everything_is_created_without_a_deployment_and_nothing_changes_after(){
+    let runtime = Runtime::build(&example()).unwrap();
+    let plan = Plan::new(&runtime, None);
+    assert!(plan.networks.values().all(|change| change.action == Action::Create));
+    assert!(plan.instances.values().all(|change| change.action == Action::Create));
+    assert_eq!(plan.instances.len(), 2);
+    assert!(Plan::new(&runtime, Some(&runtime)).is_empty());
+}
+
+#[test]
+fn compute_changes_redefine_the_domain_and_keep_the_disk(){
+    let mut config = example();
+    let vm1 = config.instances.get_mut("vm1").unwrap();
+    vm1.vcpu = 2;
+    vm1.memory = Memory::from_gib(2);
+    vm1.hugepages = Some(Memory::from_mib(2));
+    let plan = plan(&example(), &config);
+    let change = &plan.instances["vm1"];
+    assert_eq!(change.action, Action::Update);
+    assert!(change.redefine && !change.restart);
+    assert_eq!(change.details, ["~ vcpu 1 -> 2", "~ memory 1GiB -> 2GiB", "~ hugepages none -> 2MiB"]);
+    assert!(!plan.instances.contains_key("vm2"));
+}
+
+#[test]
+fn a_new_image_or_a_transient_instance_is_replaced(){
+    let mut config = example();
+    config.instances.get_mut("vm1").unwrap().image = "/var/lib/libvirt/images/other.img".to_string();
+    assert_eq!(plan(&example(), &config).instances["vm1"].action, Action::Replace);
+    let mut deployed = example();
+    deployed.instances.get_mut("vm2").unwrap().persistent = false;
+    let mut config = deployed.clone();
+    config.instances.get_mut("vm2").unwrap().vcpu = 2;
+    assert_eq!(plan(&deployed, &config).instances["vm2"].action, Action::Replace);
+}
+
+#[test]
+fn interface_changes_are_hot_plugged_and_address_changes_restart(){
+    let mut config = example();
+    config.interfaces.insert("vm1_eth2".to_string(), InterfaceConfig::new(1500, "net2", "vm1"));
+    config.interfaces.get_mut("vm2_eth1").unwrap().mtu = 9000;
+    config.interfaces.get_mut("vm2_eth2").unwrap().address = Some("10.0.1.50".parse().unwrap());
+    let plan = plan(&example(), &config);
+    let vm1 = &plan.instances["vm1"];
+    assert_eq!(vm1.action, Action::Update);
+    assert_eq!(vm1.interfaces_added.keys().collect::<Vec<_>>(), ["vm1_eth2"]);
+    assert!(vm1.interfaces_removed.is_empty() && vm1.guest_config && !vm1.restart && !vm1.redefine);
+    let vm2 = &plan.instances["vm2"];
+    assert_eq!(vm2.action, Action::Update);
+    // a changed mtu replugs the interface, a changed address is applied by the guest on boot
+    assert_eq!(vm2.interfaces_removed.keys().collect::<Vec<_>>(), ["vm2_eth1"]);
+    assert_eq!(vm2.interfaces_added.keys().collect::<Vec<_>>(), ["vm2_eth1"]);
+    assert!(vm2.restart && !vm2.redefine);
+}
+
+#[test]
+fn removed_instances_are_destroyed(){
+    let mut config = example();
+    config.instances.remove("vm2");
+    config.interfaces.retain(|_, interface| interface.instance != "vm2");
+    config.route_tables.clear();
+    let plan = plan(&example(), &config);
+    assert_eq!(plan.instances["vm2"].action, Action::Destroy);
+    assert_eq!(plan.instances["vm1"].action, Action::Update);
+}
+
+#[test]
+fn failed_instances_keep_their_deployed_runtime(){
+    let deployed = Runtime::build(&example()).unwrap();
+    let mut config = example();
+    config.instances.get_mut("vm1").unwrap().vcpu = 2;
+    config.instances.get_mut("vm2").unwrap().vcpu = 2;
+    config.instances.insert("vm3".to_string(), config.instances["vm1"].clone());
+    let runtime = Runtime::rebuild(&config, &deployed).unwrap();
+    let vm1 = "vm1".to_string();
+    let vm3 = "vm3".to_string();
+    let saved = runtime.applied(Some(&deployed), &[&vm1, &vm3]);
+    assert_eq!(saved.instances["vm1"].vcpu, 1);
+    assert_eq!(saved.instances["vm2"].vcpu, 2);
+    // never deployed, the next plan creates it again
+    assert!(!saved.instances.contains_key("vm3"));
+    let again = Plan::new(&Runtime::rebuild(&config, &saved).unwrap(), Some(&saved));
+    assert_eq!(again.instances.keys().collect::<Vec<_>>(), ["vm1", "vm3"]);
+}
+
+#[test]
+fn moved_and_removed_interfaces_are_unplugged(){
+    let mut config = example();
+    config.interfaces.remove("vm2_eth2");
+    config.interfaces.get_mut("vm1_eth1").unwrap().network = "net2".to_string();
+    config.route_tables.clear();
+    let deployed = Runtime::build(&example()).unwrap();
+    let plan = plan(&example(), &config);
+    let vm1 = &plan.instances["vm1"];
+    // the old interface is unplugged with its deployed settings, the new one plugged with the same mac
+    assert_eq!(vm1.interfaces_removed["vm1_eth1"], deployed.instances["vm1"].interfaces["vm1_eth1"]);
+    assert_eq!(vm1.interfaces_added["vm1_eth1"].network, "net2");
+    assert_eq!(vm1.interfaces_added["vm1_eth1"].mac, vm1.interfaces_removed["vm1_eth1"].mac);
+    let vm2 = &plan.instances["vm2"];
+    assert_eq!(vm2.interfaces_removed.keys().collect::<Vec<_>>(), ["vm2_eth2"]);
+    assert!(vm2.interfaces_added.is_empty() && vm2.guest_config);
+}
+
+#[test]
+fn interfaces_of_replaced_networks_are_replugged(){
+    let mut config = example();
+    config.networks.get_mut("net2").unwrap().network_type = serde_yaml::from_str("{subnet: 10.0.1.0/24, bridge_type: ovs}").unwrap();
+    let plan = plan(&example(), &config);
+    assert_eq!(plan.networks["net2"].action, Action::Replace);
+    let vm2 = &plan.instances["vm2"];
+    assert_eq!(vm2.action, Action::Update);
+    assert_eq!(vm2.interfaces_removed.keys().collect::<Vec<_>>(), ["vm2_eth2"]);
+    assert_eq!(vm2.interfaces_added.keys().collect::<Vec<_>>(), ["vm2_eth2"]);
+    assert!(!plan.instances.contains_key("vm1"));
+}
+
+#[test]
+fn more_interfaces_than_hotplug_ports_redefine_the_domain(){
+    let mut config = example();
+    for idx in 0..HOTPLUG_PORTS{
+        config.interfaces.insert(format!("vm1_eth{}", idx + 2), InterfaceConfig::new(1500, "net2", "vm1"));
+    }
+    let change = &plan(&example(), &config).instances["vm1"];
+    assert!(change.action == Action::Update && !change.redefine);
+    config.interfaces.insert("vm1_eth9".to_string(), InterfaceConfig::new(1500, "net2", "vm1"));
+    let change = &plan(&example(), &config).instances["vm1"];
+    assert!(change.action == Action::Update && change.redefine);
+    assert_eq!(change.interfaces_added.len(), HOTPLUG_PORTS + 1);
+}
+
+#[test]
+fn instances_failed_on_the_first_deployment_are_created_by_the_next_apply(){
+    let runtime = Runtime::build(&example()).unwrap();
+    let vm1 = "vm1".to_string();
+    let saved = runtime.clone().applied(None, &[&vm1]);
+    assert!(!saved.instances.contains_key("vm1"));
+    let plan = Plan::new(&Runtime::rebuild(&example(), &saved).unwrap(), Some(&saved));
+    assert_eq!(plan.instances.keys().collect::<Vec<_>>(), ["vm1"]);
+    assert_eq!(plan.instances["vm1"].action, Action::Create);
+}
+
+#[test]
+fn a_changed_template_file_redefines_the_domain(){
+    let template = std::env::temp_dir().join(format!("virt-rs-{}-plan.hbs", std::process::id()));
+    std::fs::write(&template, "<domain type='kvm'/>").unwrap();
+    let mut config = example();
+    config.instances.get_mut("vm1").unwrap().domain.template = Some(template.to_string_lossy().to_string());
+    let deployed = Runtime::build(&config).unwrap();
+    assert!(Plan::new(&Runtime::rebuild(&config, &deployed).unwrap(), Some(&deployed)).is_empty());
+    std::fs::write(&template, "<domain type='qemu'/>").unwrap();
+    let plan = Plan::new(&Runtime::rebuild(&config, &deployed).unwrap(), Some(&deployed));
+    std::fs::remove_file(&template).unwrap();
+    let change = &plan.instances["vm1"];
+    assert!(change.action == Action::Update && change.redefine);
+    assert!(change.details[0].starts_with("~ template content "), "{:?}", change.details);
+    assert!(!plan.instances.contains_key("vm2"));
+}
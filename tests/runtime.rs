@@ -0,0 +1,242 @@
+// building the runtime from the config: identities, allocation and its errors
+mod common;
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+
+use common::example;
+use virt_rs::network::network::{NetworkConfig, NetworkTypeRuntime};
+use virt_rs::runtime::runtime::{Runtime, RuntimeError};
+use virt_rs::state::state::State;
+
+fn network(yaml: &str) -> NetworkConfig{
+    serde_yaml::from_str(yaml).unwrap()
+}
+
+#[test]
+fn uuids_are_stable_version_8_uuids(){
+    let runtime = Runtime::build(&example()).unwrap();
+    let again = Runtime::build(&example()).unwrap();
+    for (name, instance) in &runtime.instances{
+        assert_eq!(instance.uuid, again.instances[name].uuid);
+        let hex: String = instance.uuid.split('-').collect();
+        assert_eq!(hex.len(), 32);
+        assert_eq!(&hex[12..13], "8", "version of {}", instance.uuid);
+        assert!(matches!(&hex[16..17], "8" | "9" | "a" | "b"), "variant of {}", instance.uuid);
+    }
+    assert_ne!(runtime.instances["vm1"].uuid, runtime.instances["vm2"].uuid);
+}
+
+#[test]
+fn rebuild_keeps_the_uuids_of_deployed_instances(){
+    let mut previous = Runtime::build(&example()).unwrap();
+    previous.instances.get_mut("vm1").unwrap().uuid = "4ec53c08-85bd-57bb-8567-7540d4a2335f".to_string();
+    let runtime = Runtime::rebuild(&example(), &previous).unwrap();
+    assert_eq!(runtime.instances["vm1"].uuid, "4ec53c08-85bd-57bb-8567-7540d4a2335f");
+}
+
+#[test]
+fn macs_are_locally_administered_and_unique(){
+    let runtime = Runtime::build(&example()).unwrap();
+    let mut macs = Vec::new();
+    for interface in runtime.instances.values().flat_map(|instance| instance.interfaces.values()){
+        assert_eq!((interface.mac.0, interface.mac.1, interface.mac.2), (0x52, 0x54, 0x00));
+        macs.push(interface.mac);
+    }
+    let count = macs.len();
+    macs.sort();
+    macs.dedup();
+    assert_eq!(macs.len(), count);
+}
+
+#[test]
+fn a_pinned_mac_colliding_with_a_generated_one_is_an_error(){
+    let generated = Runtime::build(&example()).unwrap().instances["vm1"].interfaces["vm1_eth0"].mac;
+    let mut config = example();
+    config.interfaces.get_mut("vm2_eth1").unwrap().mac = Some(generated);
+    match Runtime::build(&config){
+        Err(RuntimeError::MacConflict{mac, interface, other}) => {
+            assert_eq!(mac, generated);
+            assert_eq!((interface.as_str(), other.as_str()), ("vm2_eth1", "vm1_eth0"));
+        },
+        other => panic!("expected a mac conflict, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn bridges_of_equally_named_networks_differ_between_topologies(){
+    let lab1 = Runtime::build(&example()).unwrap();
+    let mut config = example();
+    config.name = Some("other".to_string());
+    let lab2 = Runtime::build(&config).unwrap();
+    for (name, network) in &lab1.networks{
+        let (bridge1, bridge2) = match (&network.network_type, &lab2.networks[name].network_type){
+            (NetworkTypeRuntime::Unmanaged{bridge: bridge1, ..}, NetworkTypeRuntime::Unmanaged{bridge: bridge2, ..}) => (bridge1, bridge2),
+            _ => continue,
+        };
+        assert_ne!(bridge1.name, bridge2.name);
+        assert!(bridge1.name.len() <= 15 && bridge1.name.ends_with(&format!("-{}", name)), "bridge {}", bridge1.name);
+    }
+}
+
+#[test]
+fn unknown_references_are_errors_instead_of_panics(){
+    let mut config = example();
+    config.interfaces.get_mut("vm2_eth2").unwrap().network = "net9".to_string();
+    assert_eq!(Runtime::build(&config).unwrap_err(), RuntimeError::UnknownReference{
+        kind: "network",
+        name: "net9".to_string(),
+        referenced_by: "interface vm2_eth2".to_string(),
+    });
+    let mut config = example();
+    config.route_tables.get_mut("vm1_rt1").unwrap().instance = "vm9".to_string();
+    let error = Runtime::build(&config).unwrap_err();
+    assert_eq!(error.to_string(), "unknown instance 'vm9' referenced by route table vm1_rt1");
+}
+
+#[test]
+fn invalid_and_exhausted_subnets_are_errors(){
+    let mut config = example();
+    config.networks.insert("net2".to_string(), network("network_type: {subnet: 10.0.1.0/33}"));
+    assert!(matches!(Runtime::build(&config), Err(RuntimeError::InvalidCidr{network, ..}) if network == "net2"));
+    // no room for a gateway, at the edges of the address space as well
+    for network_type in ["{subnet: 0.0.0.0/32}", "{subnet: 255.255.255.255/32}", "{subnet: 10.0.1.0/31}", "{subnet6: 'ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff/128'}"]{
+        config.networks.insert("net2".to_string(), network(&format!("network_type: {}", network_type)));
+        assert!(matches!(Runtime::build(&config), Err(RuntimeError::InvalidCidr{network, ..}) if network == "net2"), "{}", network_type);
+    }
+    config.networks.insert("net2".to_string(), network("network_type: {subnet: 10.0.1.0/24, gateway: 10.0.2.1}"));
+    assert!(matches!(Runtime::build(&config), Err(RuntimeError::AddressOutOfRange{network, ..}) if network == "net2"));
+    // a /30 has two usable addresses, the gateway and vm1_eth1 take them and none is left for vm2_eth1
+    let mut config = example();
+    config.networks.insert("net1".to_string(), network("network_type: {subnet: 10.0.0.0/30}"));
+    match Runtime::build(&config){
+        Err(RuntimeError::SubnetExhausted{network, subnet}) => {
+            assert_eq!((network.as_str(), subnet.to_string().as_str()), ("net1", "10.0.0.0/30"));
+        },
+        other => panic!("expected an exhausted subnet, got {:?}", other.map(|_| ())),
+    }
+}
+
+fn addresses(runtime: &Runtime) -> Vec<(String, Option<Ipv4Addr>)>{
+    runtime.instances.values()
+        .flat_map(|instance| instance.interfaces.iter())
+        .map(|(name, interface)| (name.clone(), interface.address))
+        .collect()
+}
+
+fn address(runtime: &Runtime, instance: &str, interface: &str) -> String{
+    runtime.instances[instance].interfaces[interface].address.unwrap().to_string()
+}
+
+#[test]
+fn addresses_are_allocated_in_name_order_and_stable_across_runs(){
+    let runtime = Runtime::build(&example()).unwrap();
+    for _ in 0..10{
+        assert_eq!(addresses(&Runtime::build(&example()).unwrap()), addresses(&runtime));
+    }
+    assert_eq!(address(&runtime, "vm1", "vm1_eth1"), "10.0.0.2");
+    assert_eq!(address(&runtime, "vm2", "vm2_eth1"), "10.0.0.3");
+    assert_eq!(address(&runtime, "vm2", "vm2_eth2"), "10.0.1.2");
+}
+
+#[test]
+fn pinned_addresses_gateway_reserved_and_allocation_ranges_are_honoured(){
+    let mut config = example();
+    config.networks.insert("net1".to_string(), network("
+network_type:
+  subnet: 10.0.0.0/24
+  gateway: 10.0.0.254
+  reserved: [{start: 10.0.0.10, end: 10.0.0.19}]
+  allocation_range: {start: 10.0.0.10, end: 10.0.0.30}
+"));
+    config.interfaces.get_mut("vm1_eth1").unwrap().address = Some("10.0.0.20".parse().unwrap());
+    let runtime = Runtime::build(&config).unwrap();
+    assert_eq!(runtime.networks["net1"].gateway(), Some("10.0.0.254".parse().unwrap()));
+    assert_eq!(address(&runtime, "vm1", "vm1_eth1"), "10.0.0.20");
+    // the reserved range is skipped and the pinned address isn't handed out twice
+    assert_eq!(address(&runtime, "vm2", "vm2_eth1"), "10.0.0.21");
+}
+
+#[test]
+fn pinned_addresses_must_be_usable_and_unique(){
+    let mut config = example();
+    config.interfaces.get_mut("vm1_eth1").unwrap().address = Some("10.0.1.5".parse().unwrap());
+    assert!(matches!(Runtime::build(&config), Err(RuntimeError::AddressOutOfRange{interface, ..}) if interface == "vm1_eth1"));
+    let mut config = example();
+    config.interfaces.get_mut("vm1_eth1").unwrap().address = Some("10.0.0.255".parse().unwrap());
+    assert!(matches!(Runtime::build(&config), Err(RuntimeError::AddressOutOfRange{..})));
+    let mut config = example();
+    config.interfaces.get_mut("vm1_eth1").unwrap().address = Some("10.0.0.7".parse().unwrap());
+    config.interfaces.get_mut("vm2_eth1").unwrap().address = Some("10.0.0.7".parse().unwrap());
+    assert!(matches!(Runtime::build(&config), Err(RuntimeError::AddressConflict{interface, ..}) if interface == "vm2_eth1"));
+    // the gateway is taken as well
+    let mut config = example();
+    config.interfaces.get_mut("vm1_eth1").unwrap().address = Some("10.0.0.1".parse().unwrap());
+    assert!(matches!(Runtime::build(&config), Err(RuntimeError::AddressConflict{interface, ..}) if interface == "vm1_eth1"));
+}
+
+#[test]
+fn ipv6_only_and_dual_stack_networks_assign_addresses(){
+    let mut config = example();
+    config.networks.insert("net1".to_string(), network("network_type: {subnet: 10.0.0.0/24, subnet6: 'fd00:1::/64'}"));
+    config.networks.insert("net2".to_string(), network("network_type: {subnet6: 'fd00:2::/64', address_mode6: eui64}"));
+    config.validate().unwrap();
+    let runtime = Runtime::build(&config).unwrap();
+    let vm1_eth1 = &runtime.instances["vm1"].interfaces["vm1_eth1"];
+    assert_eq!(vm1_eth1.address, Some("10.0.0.2".parse().unwrap()));
+    assert_eq!(vm1_eth1.address6, Some("fd00:1::2".parse().unwrap()));
+    assert_eq!(runtime.networks["net1"].gateway6(), Some("fd00:1::1".parse().unwrap()));
+    let vm2_eth2 = &runtime.instances["vm2"].interfaces["vm2_eth2"];
+    assert_eq!(vm2_eth2.address, None);
+    let mac = vm2_eth2.mac;
+    let eui64 = format!("fd00:2::{:x}:{:x}ff:fe{:x}:{:x}", 0x5054, mac.2, mac.3, u16::from(mac.4) << 8 | u16::from(mac.5));
+    assert_eq!(vm2_eth2.address6, Some(eui64.parse().unwrap()));
+    // the route of vm1 to the ipv6 only net2 goes through the ipv6 address of vm2 on net1
+    let routes = &runtime.instances["vm1"].route_tables["vm1_rt1"].routes;
+    assert_eq!(routes[&"fd00:2::/64".parse().unwrap()], [runtime.instances["vm2"].interfaces["vm2_eth1"].address6.map(IpAddr::V6).unwrap()]);
+}
+
+#[test]
+fn eui64_needs_a_64_prefix(){
+    let mut config = example();
+    config.networks.insert("net2".to_string(), network("network_type: {subnet6: 'fd00:2::/80', address_mode6: eui64}"));
+    let errors = config.validate().unwrap_err().0;
+    assert_eq!(errors[0].path, "networks.net2.network_type.address_mode6");
+}
+
+#[test]
+fn rebuild_keeps_addresses_unless_they_left_the_allocation_range(){
+    let mut previous = Runtime::build(&example()).unwrap();
+    previous.instances.get_mut("vm2").unwrap().interfaces.get_mut("vm2_eth1").unwrap().address = Some("10.0.0.50".parse().unwrap());
+    previous.instances.get_mut("vm2").unwrap().interfaces.get_mut("vm2_eth2").unwrap().address = Some("10.0.1.50".parse().unwrap());
+    let mut config = example();
+    config.networks.insert("net1".to_string(), network("network_type: {subnet: 10.0.0.0/24, reserved: [{start: 10.0.0.2, end: 10.0.0.9}]}"));
+    config.networks.insert("net2".to_string(), network("network_type: {subnet: 10.0.1.0/24, allocation_range: {start: 10.0.1.100, end: 10.0.1.200}}"));
+    let runtime = Runtime::rebuild(&config, &previous).unwrap();
+    assert_eq!(address(&runtime, "vm2", "vm2_eth1"), "10.0.0.50");
+    // 10.0.0.2 was given to vm1_eth1 before and is reserved now
+    assert_eq!(address(&runtime, "vm1", "vm1_eth1"), "10.0.0.10");
+    assert_eq!(address(&runtime, "vm2", "vm2_eth2"), "10.0.1.100");
+}
+
+#[test]
+fn ipv6_addresses_survive_the_state_file(){
+    let mut config = example();
+    config.networks.insert("net1".to_string(), network("network_type: {subnet: 10.0.0.0/24, subnet6: 'fd00:1::/64'}"));
+    let runtime = Runtime::build(&config).unwrap();
+    let loaded: Runtime = serde_yaml::from_str(&serde_yaml::to_string(&runtime).unwrap()).unwrap();
+    match (&runtime.networks["net1"].network_type, &loaded.networks["net1"].network_type){
+        (NetworkTypeRuntime::Unmanaged{ipv6: Some(ipv6), ..}, NetworkTypeRuntime::Unmanaged{ipv6: Some(loaded), ..}) => {
+            assert_eq!(ipv6.addresses, loaded.addresses);
+            assert_eq!(loaded.addresses.len(), 3);
+        },
+        _ => panic!("net1 lost its ipv6 runtime"),
+    }
+}
+
+#[test]
+fn relative_state_dirs_are_next_to_the_config(){
+    assert_eq!(State::path("/srv/lab/config.yaml", ".virt-rs", "lab"), Path::new("/srv/lab/.virt-rs/lab.yaml"));
+    assert_eq!(State::path("config.yaml", ".virt-rs", "lab"), Path::new(".virt-rs/lab.yaml"));
+    assert_eq!(State::path("/srv/lab/config.yaml", "/var/lib/virt-rs", "lab"), Path::new("/var/lib/virt-rs/lab.yaml"));
+}
@@ -0,0 +1,233 @@
+// brings the example topology up against the libvirt test driver, which simulates a host
+// in memory and needs neither kvm nor root
+mod common;
+
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use common::example;
+use virt::domain::Domain;
+use virt::network::Network;
+use virt::storage_pool::StoragePool;
+use virt::storage_vol::StorageVol;
+use virt_rs::backend::backend::{Backend, DryRun, Operation};
+use virt_rs::config::config::Config;
+use virt_rs::interface::interface::InterfaceConfig;
+use virt_rs::network::network::{NetworkConfig, NetworkMode, NetworkTypeConfig, NetworkTypeRuntime};
+use virt_rs::object::object::Object;
+use virt_rs::runtime::runtime::Runtime;
+use virt_rs::state::state::Resources;
+use virt_rs::virt_manager::virt_manager::{seed_iso_file, VirtManager};
+
+const TEST_URI: &str = "test:///default";
+// test:///default comes with this pool and a network named default
+const POOL: &str = "default-pool";
+const IMAGE: &str = "cloudimg.qcow2";
+const IMAGE_XML: &str = r#"
+<volume>
+  <name>cloudimg.qcow2</name>
+  <capacity unit='GiB'>2</capacity>
+  <target>
+    <format type='qcow2'/>
+  </target>
+</volume>
+"#;
+
+// all connections to test:///default within a process share one simulated host
+static HOST: Mutex<()> = Mutex::new(());
+
+struct Lab{
+    virt_manager: VirtManager,
+    runtime: Runtime,
+    resources: Resources,
+    _host: MutexGuard<'static, ()>,
+}
+
+impl Lab{
+    fn up() -> Lab{
+        let host = HOST.lock().unwrap_or_else(|e| e.into_inner());
+        let runtime = Runtime::build(&config()).unwrap();
+        // genisoimage only runs in the dry run host, the isos it would write are given instead
+        let files = runtime.instances.keys().map(|name| (seed_iso_file(name), seed_iso(name))).collect();
+        let virt_manager = VirtManager::with_host(TEST_URI, Box::new(DryRun::with_files(files))).unwrap();
+        let pool = StoragePool::lookup_by_name(&virt_manager.conn, POOL).unwrap();
+        StorageVol::create_xml(&pool, IMAGE_XML, 0).unwrap();
+        let mut resources = Resources::default();
+        let results = virt_manager.create_topology(&runtime, &mut resources).unwrap();
+        for (name, result) in &results{
+            assert!(result.is_ok(), "instance {} failed: {:?}", name, result);
+        }
+        Lab{
+            virt_manager,
+            runtime,
+            resources,
+            _host: host,
+        }
+    }
+
+    fn domain(&self, name: &str) -> Domain{
+        Domain::lookup_by_name(&self.virt_manager.conn, name).unwrap()
+    }
+
+    fn libvirt_network(&self) -> String{
+        match &self.runtime.networks["lab"].network_type{
+            NetworkTypeRuntime::Libvirt{name, ..} => name.clone(),
+            _ => unreachable!(),
+        }
+    }
+}
+
+// leaves the shared host as it was found, also when an assertion failed
+impl Drop for Lab{
+    fn drop(&mut self){
+        self.virt_manager.destroy_topology(&self.runtime.name, &mut self.resources, Duration::from_secs(1)).ok();
+        if let Ok(pool) = StoragePool::lookup_by_name(&self.virt_manager.conn, POOL){
+            if let Ok(image) = StorageVol::lookup_by_name(&pool, IMAGE){
+                image.delete(0).ok();
+            }
+        }
+        self.virt_manager.disconnect().ok();
+    }
+}
+
+fn seed_iso(name: &str) -> Vec<u8>{
+    format!("cidata of {}", name).repeat(100).into_bytes()
+}
+
+// the example topology plus a network created through libvirt
+fn config() -> Config{
+    let mut config = example();
+    config.storage.pool = POOL.to_string();
+    for instance in config.instances.values_mut(){
+        instance.image = IMAGE.to_string();
+    }
+    config.add("lab", NetworkConfig::new(NetworkTypeConfig::Libvirt{
+        mode: NetworkMode::Nat,
+        subnet: "192.168.100.0/24".to_string(),
+        dhcp_range: None,
+        domain: None,
+    }));
+    config.add("vm1_eth2", InterfaceConfig::new(1500, "lab", "vm1"));
+    config.add("vm2_eth3", InterfaceConfig::new(1500, "lab", "vm2"));
+    config.validate().unwrap();
+    config
+}
+
+#[test]
+fn up_defines_and_starts_every_instance(){
+    let lab = Lab::up();
+    for (name, instance) in &lab.runtime.instances{
+        let domain = lab.domain(name);
+        assert!(domain.is_active().unwrap(), "instance {} is not running", name);
+        assert_eq!(domain.get_autostart().unwrap(), instance.autostart);
+        assert_eq!(domain.get_uuid_string().unwrap(), instance.uuid);
+        assert_eq!(lab.resources.instances[name].domain, *name);
+    }
+}
+
+#[test]
+fn domains_have_one_interface_per_topology_interface(){
+    let lab = Lab::up();
+    for (name, instance) in &lab.runtime.instances{
+        let xml = lab.domain(name).get_xml_desc(0).unwrap();
+        assert_eq!(xml.matches("<interface ").count(), instance.interfaces.len(), "interfaces of instance {}", name);
+        for (interface_name, interface) in &instance.interfaces{
+            let mac = format!("<mac address='{}'/>", interface.mac);
+            let interface_xml = xml.split("<interface ").find(|element| element.contains(&mac))
+                .unwrap_or_else(|| panic!("interface {} missing in domain {}", interface_name, name));
+            let source = match (&interface.managed, &interface.bridge){
+                (Some(network), _) => format!("<source network='{}'", network),
+                (None, Some(bridge)) => format!("<source bridge='{}'", bridge.name),
+                (None, None) => unreachable!(),
+            };
+            assert!(interface_xml.contains(&source), "interface {} is not connected to {}", interface_name, source);
+        }
+    }
+}
+
+#[test]
+fn libvirt_networks_get_a_dhcp_host_per_interface(){
+    let lab = Lab::up();
+    let libvirt_name = lab.libvirt_network();
+    assert_eq!(lab.resources.libvirt_networks["lab"], libvirt_name);
+    let network = Network::lookup_by_name(&lab.virt_manager.conn, &libvirt_name).unwrap();
+    assert!(network.is_active().unwrap());
+    let xml = network.get_xml_desc(0).unwrap();
+    for interface in lab.runtime.instances.values().flat_map(|instance| instance.interfaces.values()){
+        if interface.network != "lab"{
+            continue;
+        }
+        let host = xml.split("<host ").find(|host| host.contains(&format!("mac='{}'", interface.mac))).unwrap();
+        assert!(host.contains(&format!("ip='{}'", interface.address.unwrap())));
+    }
+}
+
+#[test]
+fn disks_and_seed_isos_are_volumes_of_the_pool(){
+    let lab = Lab::up();
+    let pool = StoragePool::lookup_by_name(&lab.virt_manager.conn, POOL).unwrap();
+    let image = StorageVol::lookup_by_name(&pool, IMAGE).unwrap().get_path().unwrap();
+    for (name, resources) in &lab.resources.instances{
+        assert_eq!(resources.pool.as_deref(), Some(POOL));
+        assert_eq!(resources.volumes.len(), 2);
+        for volume in &resources.volumes{
+            assert!(StorageVol::lookup_by_name(&pool, volume).is_ok(), "volume {} of instance {} missing", volume, name);
+        }
+        // disks are overlays of the image by default
+        let disk = StorageVol::lookup_by_name(&pool, &resources.volumes[0]).unwrap();
+        assert!(disk.get_xml_desc(0).unwrap().contains(&image));
+        // the seed iso volume is as large as the iso streamed into it
+        let seed_iso_volume = StorageVol::lookup_by_name(&pool, &resources.volumes[1]).unwrap();
+        assert_eq!(seed_iso_volume.get_info().unwrap().capacity, seed_iso(name).len() as u64);
+    }
+}
+
+#[test]
+fn down_removes_domains_networks_and_volumes(){
+    let mut lab = Lab::up();
+    let volumes: Vec<String> = lab.resources.instances.values().flat_map(|resources| resources.volumes.clone()).collect();
+    let results = lab.virt_manager.destroy_topology(&lab.runtime.name, &mut lab.resources, Duration::from_secs(1)).unwrap();
+    assert!(results.values().all(|result| result.is_ok()));
+    assert!(lab.resources.is_empty());
+    for name in lab.runtime.instances.keys(){
+        assert!(Domain::lookup_by_name(&lab.virt_manager.conn, name).is_err(), "instance {} still defined", name);
+    }
+    assert!(Network::lookup_by_name(&lab.virt_manager.conn, &lab.libvirt_network()).is_err());
+    let pool = StoragePool::lookup_by_name(&lab.virt_manager.conn, POOL).unwrap();
+    for volume in volumes{
+        assert!(StorageVol::lookup_by_name(&pool, &volume).is_err(), "volume {} still exists", volume);
+    }
+}
+
+#[test]
+fn domains_of_another_topology_are_neither_taken_over_nor_destroyed(){
+    let lab = Lab::up();
+    let mut config = config();
+    config.name = Some("other".to_string());
+    let other = Runtime::build(&config).unwrap();
+    for (name, instance) in &other.instances{
+        assert!(lab.virt_manager.check_owner(name, instance).is_err(), "instance {} taken over", name);
+    }
+    let mut resources = Resources{
+        instances: Resources::from(&other).instances,
+        ..Default::default()
+    };
+    let results = lab.virt_manager.destroy_topology(&other.name, &mut resources, Duration::from_secs(1)).unwrap();
+    assert!(results.values().all(|result| result.is_ok()));
+    for name in other.instances.keys(){
+        assert!(lab.domain(name).is_active().unwrap(), "instance {} was destroyed", name);
+    }
+}
+
+#[test]
+fn a_dry_run_skips_what_is_already_deployed(){
+    let lab = Lab::up();
+    let dry_run = DryRun::inspecting(VirtManager::read_only(TEST_URI).unwrap());
+    let results = dry_run.create_topology(&lab.runtime, &mut Resources::default()).unwrap();
+    assert!(results.values().all(|result| result.is_ok()));
+    let recreated: Vec<Operation> = dry_run.operations().into_iter()
+        .filter(|operation| matches!(operation, Operation::CreateDisk{..} | Operation::UploadVolume{..} |
+            Operation::DefineDomain{..} | Operation::CreateDomain{..} | Operation::DefineNetwork{..}))
+        .collect();
+    assert!(recreated.is_empty(), "{:?}", recreated);
+}
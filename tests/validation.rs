@@ -0,0 +1,149 @@
+// config validation: every problem is reported with the path of the offending field
+mod common;
+
+use std::collections::BTreeSet;
+
+use common::example;
+use virt_rs::config::config::Config;
+use virt_rs::instance::instance::{CpuMode, CpuTopology, Memory, NumaCell};
+use virt_rs::interface::interface::InterfaceConfig;
+use virt_rs::network::network::NetworkTypeConfig;
+use virt_rs::route_table::route_table::InstanceInterface;
+use virt_rs::xml::xml::XmlPatch;
+
+fn error_paths(config: &Config) -> BTreeSet<String>{
+    config.validate().unwrap_err().0.into_iter().map(|error| error.path).collect()
+}
+
+#[test]
+fn the_example_and_the_shipped_config_are_valid(){
+    assert!(example().validate().unwrap().is_empty());
+    let config: Config = serde_yaml::from_str(&std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/config.yaml")).unwrap()).unwrap();
+    let warnings = config.validate().unwrap();
+    let paths: Vec<&str> = warnings.iter().map(|warning| warning.path.as_str()).collect();
+    assert_eq!(paths, ["route_tables.host2_rt1.routes.access1[0].interface"]);
+}
+
+#[test]
+fn unknown_references_are_all_reported(){
+    let mut config = example();
+    config.interfaces.get_mut("vm1_eth1").unwrap().network = "net9".to_string();
+    config.interfaces.get_mut("vm2_eth1").unwrap().instance = "vm9".to_string();
+    config.route_tables.get_mut("vm1_rt1").unwrap().routes.insert("net8".to_string(), vec![InstanceInterface{
+        instance: "vm2".to_string(),
+        interface: "vm2_eth7".to_string(),
+    }]);
+    let paths = error_paths(&config);
+    for path in [
+        "interfaces.vm1_eth1.network",
+        "interfaces.vm2_eth1.instance",
+        "route_tables.vm1_rt1.routes.net8",
+        "route_tables.vm1_rt1.routes.net8[0].interface",
+    ]{
+        assert!(paths.contains(path), "{} not in {:?}", path, paths);
+    }
+}
+
+#[test]
+fn limits_and_subnets_are_checked(){
+    let mut config = example();
+    config.instances.get_mut("vm1").unwrap().vcpu = 0;
+    config.interfaces.get_mut("vm1_eth1").unwrap().mtu = 10;
+    if let NetworkTypeConfig::Unmanaged{subnet, ..} = &mut config.networks.get_mut("net2").unwrap().network_type{
+        *subnet = Some("10.0.0.128/25".to_string());
+    }
+    if let NetworkTypeConfig::Unmanaged{gateway, ..} = &mut config.networks.get_mut("net1").unwrap().network_type{
+        *gateway = Some("10.0.0.255".parse().unwrap());
+    }
+    assert_eq!(error_paths(&config), BTreeSet::from([
+        "instances.vm1.vcpu".to_string(),
+        "interfaces.vm1_eth1.mtu".to_string(),
+        "networks.net1.network_type.gateway".to_string(),
+        "networks.net2.network_type.subnet".to_string(),
+    ]));
+}
+
+#[test]
+fn too_many_interfaces_and_long_names_are_rejected(){
+    let mut config = example();
+    for idx in 0..10{
+        config.interfaces.insert(format!("vm1_extra{}", idx), InterfaceConfig::new(1500, "net1", "vm1"));
+    }
+    config.interfaces.insert("vm2_interface_name".to_string(), InterfaceConfig::new(1500, "net2", "vm2"));
+    let paths = error_paths(&config);
+    assert!(paths.contains("instances.vm1"), "{:?}", paths);
+    assert!(paths.contains("interfaces.vm2_interface_name"), "{:?}", paths);
+}
+
+#[test]
+fn next_hops_off_the_links_of_the_instance_are_warnings(){
+    let mut config = example();
+    config.route_tables.get_mut("vm1_rt1").unwrap().routes.get_mut("net2").unwrap()[0].interface = "vm2_eth2".to_string();
+    let warnings = config.validate().unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].path, "route_tables.vm1_rt1.routes.net2[0].interface");
+}
+
+#[test]
+fn inconsistent_compute_settings_are_rejected(){
+    let mut config = example();
+    let vm1 = config.instances.get_mut("vm1").unwrap();
+    vm1.vcpu = 4;
+    vm1.memory = Memory::from_mib(64);
+    vm1.hugepages = Some(Memory::from_kib(3000));
+    vm1.cpu.mode = Some(CpuMode::Custom);
+    vm1.cpu.topology = Some(CpuTopology{sockets: 1, cores: 2, threads: 1});
+    vm1.cpu.pinning = [(0, "2".to_string()), (4, "1-0".to_string())].into();
+    vm1.cpu.numa = vec![
+        NumaCell{cpus: "0-1".to_string(), memory: Memory::from_gib(2)},
+        NumaCell{cpus: "1-4".to_string(), memory: Memory::from_mib(2048)},
+    ];
+    assert_eq!(error_paths(&config), BTreeSet::from([
+        "instances.vm1.memory",
+        "instances.vm1.hugepages",
+        "instances.vm1.cpu.model",
+        "instances.vm1.cpu.topology",
+        "instances.vm1.cpu.pinning.4",
+        "instances.vm1.cpu.numa[1].cpus",
+        "instances.vm1.cpu.numa[0].memory",
+        "instances.vm1.cpu.numa[1].memory",
+        "instances.vm1.cpu.numa",
+    ].map(String::from)));
+}
+
+#[test]
+fn memory_beyond_the_maximum_is_a_warning(){
+    let mut config = example();
+    config.instances.get_mut("vm1").unwrap().memory = Memory::from_gib(1024);
+    let warnings = config.validate().unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].path, "instances.vm1.memory");
+}
+
+#[test]
+fn invalid_patches_are_rejected(){
+    let mut config = example();
+    config.instances.get_mut("vm1").unwrap().domain.patches = vec![
+        XmlPatch::Set{set: "domain/cpu".to_string(), value: "".to_string()},
+        XmlPatch::Append{append: "/domain/devices".to_string(), xml: "<serial type='tcp'>".to_string()},
+        XmlPatch::Append{append: "/domain/@type".to_string(), xml: "<cpu/>".to_string()},
+    ];
+    let errors = config.validate().unwrap_err().0;
+    let paths: Vec<&str> = errors.iter().map(|error| error.path.as_str()).collect();
+    assert_eq!(paths, ["instances.vm1.domain.patches[0]", "instances.vm1.domain.patches[1]", "instances.vm1.domain.patches[2]"]);
+}
+
+#[test]
+fn names_are_restricted_to_letters_digits_dashes_and_underscores(){
+    let mut config = example();
+    let vm1 = config.instances.remove("vm1").unwrap();
+    config.instances.insert("vm1/../x".to_string(), vm1);
+    config.instances.insert("v".repeat(64), config.instances["vm2"].clone());
+    let net1 = config.networks.remove("net1").unwrap();
+    config.networks.insert("net 1".to_string(), net1);
+    config.interfaces.insert("eth;reboot".to_string(), InterfaceConfig::new(1500, "net2", "vm2"));
+    let paths = error_paths(&config);
+    for path in ["instances.vm1/../x", &format!("instances.{}", "v".repeat(64)), "networks.net 1", "interfaces.eth;reboot"]{
+        assert!(paths.contains(path), "{} not in {:?}", path, paths);
+    }
+}
@@ -0,0 +1,85 @@
+// the xml parser reads what libvirt hands back, it has to survive everything libvirt may put in there
+use virt_rs::xml::xml::{Element, XmlPatch};
+
+#[test]
+fn entities_and_character_references_are_decoded(){
+    let element = Element::parse(r#"<description title="a &quot;lab&quot; &amp; more">&lt;vm&gt; &apos;1&apos; &#233;t&#xE9; &#X41;</description>"#).unwrap();
+    assert_eq!(element.attribute("title"), Some(r#"a "lab" & more"#));
+    assert_eq!(element.text.as_deref(), Some("<vm> '1' été A"));
+    // the written document reads back the same
+    assert_eq!(Element::parse(&element.to_string()).unwrap(), element);
+}
+
+#[test]
+fn unknown_references_are_kept(){
+    let element = Element::parse("<title>&nbsp; &#xZZ; & done</title>").unwrap();
+    assert_eq!(element.text.as_deref(), Some("&nbsp; &#xZZ; & done"));
+}
+
+#[test]
+fn cdata_is_taken_literally(){
+    let element = Element::parse("<metadata><![CDATA[<b>&amp;</b>]]></metadata>").unwrap();
+    assert_eq!(element.text.as_deref(), Some("<b>&amp;</b>"));
+    assert!(element.children.is_empty());
+}
+
+#[test]
+fn comments_declarations_and_mixed_content(){
+    let xml = r#"<?xml version="1.0"?>
+<!DOCTYPE domain>
+<!-- generated -->
+<domain type='kvm'>
+  head <!-- inline --> <name>vm1</name> tail
+  <devices><interface type='bridge'><mac address='52:54:00:00:00:01'/></interface></devices>
+</domain>
+"#;
+    let domain = Element::parse(xml).unwrap();
+    assert_eq!(domain.attribute("type"), Some("kvm"));
+    assert_eq!(domain.find("name").and_then(|name| name.text.as_deref()), Some("vm1"));
+    assert_eq!(domain.find_path("devices/interface/mac").and_then(|mac| mac.attribute("address")), Some("52:54:00:00:00:01"));
+    // libvirt has no mixed content, the text around children is joined
+    assert_eq!(domain.text.as_deref(), Some("head   tail"));
+}
+
+#[test]
+fn malformed_documents_are_rejected(){
+    for xml in ["<a><b></a>", "<a>", "<a x=1/>", "<a/><b/>", "<a><![CDATA[x</a>", ""]{
+        assert!(Element::parse(xml).is_err(), "{} parsed", xml);
+    }
+}
+
+#[test]
+fn doctypes_with_an_internal_subset_are_skipped(){
+    let xml = r#"<!DOCTYPE domain [
+  <!ENTITY name 'a > b'>
+  <!-- don't [stop] here > -->
+  <!ATTLIST domain type CDATA "kvm">
+]>
+<domain type='kvm'><name>vm1</name></domain>"#;
+    let domain = Element::parse(xml).unwrap();
+    assert_eq!(domain.attribute("type"), Some("kvm"));
+    assert_eq!(domain.find("name").and_then(|name| name.text.as_deref()), Some("vm1"));
+    assert!(Element::parse("<!DOCTYPE domain [<!ENTITY x 'y'>\n<domain/>").is_err());
+}
+
+#[test]
+fn patch_paths_keep_slashes_inside_predicates(){
+    let xml = r#"<domain><devices>
+  <disk device='disk'><source file='/var/lib/a.img'/><target dev='vda'/></disk>
+  <disk device='disk'><source file='/var/lib/x.img'/><target dev='vdb'/></disk>
+  <interface type='bridge'><alias name='net/0'/><source bridge='br0'/></interface>
+  <interface type='bridge'><alias name='net/1'/><source bridge='br1'/></interface>
+</devices></domain>"#;
+    let mut domain = Element::parse(xml).unwrap();
+    XmlPatch::Set{set: "/domain/devices/disk[source/@file='/var/lib/x.img']/target/@dev".to_string(), value: "sdb".to_string()}.apply(&mut domain).unwrap();
+    XmlPatch::Set{set: r#"/domain/devices/interface[alias/@name="net/0"]/source/@bridge"#.to_string(), value: "br2".to_string()}.apply(&mut domain).unwrap();
+    let targets: Vec<_> = domain.find_path("devices").unwrap().children.iter()
+        .filter_map(|child| child.find_path("target").or(child.find_path("source")))
+        .filter_map(|element| element.attribute("dev").or(element.attribute("bridge")))
+        .collect();
+    assert_eq!(targets, ["vda", "sdb", "br2", "br1"]);
+    // nothing matches, or the predicate is not closed
+    for path in ["/domain/devices/disk[source/@file='/x']/target/@dev", "/domain/devices/disk[source/@file='/x/target/@dev", "/domain/devices/disk[@a='x']]/target"]{
+        assert!(XmlPatch::Set{set: path.to_string(), value: "x".to_string()}.apply(&mut domain).is_err(), "{} applied", path);
+    }
+}